@@ -0,0 +1,140 @@
+use crate::utils::env_parse;
+use env_logger::{Builder, Target};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+
+/// A log file that is rotated once it grows past `max_bytes`.
+///
+/// Rotated files are renamed to `<path>.1`, `<path>.2`, ... with the oldest
+/// beyond `max_files` being deleted.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, idx: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", idx));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for idx in (1..self.max_files).rev() {
+                let from = self.rotated_path(idx);
+                if from.is_file() {
+                    fs::rename(from, self.rotated_path(idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Reads a numeric setting, exiting if it is malformed as there is no logger
+/// to report it to yet
+fn env_number<T: FromStr>(key: &str) -> Option<T> {
+    env_parse(key).unwrap_or_else(|why| {
+        eprintln!("{:#}", why);
+        process::exit(1)
+    })
+}
+
+/// Sets up logging to stderr, or to a rotating file when `LOG_FILE` is set
+pub fn init() {
+    let mut builder = Builder::from_default_env();
+
+    if let Ok(path) = env::var("LOG_FILE") {
+        let max_bytes = env_number("LOG_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES);
+        let max_files = env_number("LOG_MAX_FILES").unwrap_or(DEFAULT_MAX_FILES);
+
+        match RotatingFile::open(Path::new(&path), max_bytes, max_files) {
+            Ok(file) => {
+                builder.target(Target::Pipe(Box::new(file)));
+            }
+            Err(why) => eprintln!("Failed to open log file {:?}, using stderr: {}", path, why),
+        }
+    }
+
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_files_rotate_past_their_size() {
+        let dir = env::temp_dir().join(format!("hermes-test-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hermes.log");
+        let mut file = RotatingFile::open(&path, 8, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(dir.join("hermes.log.1")), "third\n");
+        assert_eq!(read(dir.join("hermes.log.2")), "second\n");
+        assert!(!dir.join("hermes.log.3").exists());
+
+        // Reopening keeps appending to the current file
+        let mut file = RotatingFile::open(&path, 64, 2).unwrap();
+        file.write_all(b"fifth\n").unwrap();
+        assert_eq!(read(path), "fourth\nfifth\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
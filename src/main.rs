@@ -4,24 +4,51 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
-use bollard::Docker;
+use bollard::{models::RestartPolicy, Docker};
 use dotenv::dotenv;
-use hyper::Server;
+use hyper::{server::accept, Server};
 use std::{
+    collections::HashSet,
     env,
     net::SocketAddr,
-    path::{Path, PathBuf},
+    path::Path,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::{fs, sync::mpsc};
+use tokio::{
+    fs,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
 
 mod utils;
-use utils::docker::run_container;
+use utils::{
+    docker::{
+        create_container, find_managed_container, halt_container, list_managed_containers,
+        remove_container, rename_container, run_container, spawn_takeover, start_container,
+        stop_container, sync_container, NAME_LABEL,
+    },
+    env_flag, env_parse, env_setting, tls,
+};
+
+mod logging;
 
 mod config;
-use config::Config;
+use config::{config_files, config_path, find_config, restart_policy, Config, Registry};
+
+mod provider;
 
 mod req_handler;
-use req_handler::MakeReqHandler;
+use req_handler::{
+    drain_deploys, load_secret, remove_stale_worktrees, spawn_crash_watcher, spawn_poller,
+    spawn_update, MakeReqHandler,
+};
+
+mod state;
+use state::{History, Journal, StatusMap};
 
 #[cfg(feature = "mimalloc")]
 #[global_allocator]
@@ -29,115 +56,701 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 static PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
+/// How many times the takeover helper tries to start an instance
+const TAKEOVER_ATTEMPTS: u32 = 8;
+/// Delay before the second attempt, doubling with each attempt after that
+const TAKEOVER_BACKOFF: Duration = Duration::from_secs(1);
+const TAKEOVER_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// When set, deploys are only logged instead of touching Docker or the repos
+pub static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// Set once the configs have been scanned and Docker is reachable
+pub static READY: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     static ref DOCKER: Docker = Docker::connect_with_local_defaults().unwrap();
     static ref CONFIGS_DIR: String =
         env::var("CONFIGS_DIR").unwrap_or_else(|_| "configs".to_string());
     static ref REPOS_DIR: String = env::var("REPOS_DIR").unwrap_or_else(|_| "repos".to_string());
-    static ref PORT: u16 = env::var("PORT")
+    static ref STATE_DIR: String = env::var("STATE_DIR").unwrap_or_else(|_| "state".to_string());
+    static ref JOURNAL: Journal = Journal::new(&*STATE_DIR);
+    static ref DEPLOYS: StatusMap = StatusMap::new(256);
+    static ref HISTORY: History = History::new(
+        &*STATE_DIR,
+        env_setting("HISTORY_SIZE")
+            .filter(|&size| size > 0)
+            .unwrap_or(1000)
+    );
+    static ref CONFIGS: Registry = Registry::default();
+    static ref SHUTDOWN_GRACE: Duration =
+        Duration::from_secs(env_setting("SHUTDOWN_GRACE").unwrap_or(30));
+    static ref PORT: u16 = env_setting("PORT").unwrap_or(4567);
+    static ref TLS_CERT: Option<String> = env::var("TLS_CERT").ok();
+    static ref TLS_KEY: Option<String> = env::var("TLS_KEY").ok();
+    /// How long a new container may take to become healthy before its
+    /// post-deploy hook is given up on
+    static ref HEALTH_TIMEOUT: Duration =
+        Duration::from_secs(env_setting("HEALTH_TIMEOUT").unwrap_or(60));
+    /// Crash loop detection is off unless a maximum number of starts is set
+    static ref CRASH_LOOP_STARTS: Option<usize> =
+        env_setting("CRASH_LOOP_STARTS").filter(|&starts| starts > 0);
+    static ref CRASH_LOOP_WINDOW: Duration =
+        Duration::from_secs(env_setting("CRASH_LOOP_WINDOW").unwrap_or(5 * 60));
+    /// How much build or container output is kept for errors and log responses
+    static ref OUTPUT_TAIL_BYTES: usize = output_tail_bytes()
+        .unwrap_or_else(|why| panic!("{:#}", why))
+        .unwrap_or(64 * 1024);
+    /// Restart policy of containers whose config doesn't set one, checked at startup
+    static ref DEFAULT_RESTART_POLICY: Option<RestartPolicy> =
+        default_restart_policy().ok().flatten();
+    /// How many times creating and starting a container is tried when it races
+    /// the container it replaces
+    static ref START_ATTEMPTS: u32 = env_setting("START_ATTEMPTS")
+        .filter(|&n| n > 0)
+        .unwrap_or(3);
+    static ref START_RETRY_DELAY: Duration =
+        Duration::from_secs(env_setting("START_RETRY_DELAY").unwrap_or(2));
+    /// How long a clone or fetch may take before it is aborted
+    static ref GIT_TIMEOUT: Duration =
+        Duration::from_secs(env_setting("GIT_TIMEOUT").unwrap_or(5 * 60));
+}
+
+/// Parses `DEFAULT_RESTART_POLICY`, or `None` if it isn't set
+fn default_restart_policy() -> anyhow::Result<Option<RestartPolicy>> {
+    env::var("DEFAULT_RESTART_POLICY")
         .ok()
-        .map(|port| port.parse().ok())
-        .flatten()
-        .unwrap_or(4567);
+        .map(|policy| {
+            restart_policy(&policy)
+                .map_err(|why| anyhow::anyhow!("invalid DEFAULT_RESTART_POLICY: {}", why))
+        })
+        .transpose()
+}
+
+/// Parses `LOG_MAX_KB` into bytes, or `None` if it isn't set
+fn output_tail_bytes() -> anyhow::Result<Option<usize>> {
+    env_parse::<usize>("LOG_MAX_KB")?
+        .map(|kb| {
+            kb.checked_mul(1024)
+                .ok_or_else(|| anyhow::anyhow!("invalid LOG_MAX_KB value '{}'", kb))
+        })
+        .transpose()
+}
+
+/// Checks the settings, so a malformed one fails at startup instead of
+/// hermes running with its default
+fn check_settings() -> anyhow::Result<()> {
+    env_parse::<usize>("HISTORY_SIZE")?;
+    env_parse::<u64>("SHUTDOWN_GRACE")?;
+    env_parse::<u16>("PORT")?;
+    env_parse::<usize>("CRASH_LOOP_STARTS")?;
+    env_parse::<u64>("CRASH_LOOP_WINDOW")?;
+    output_tail_bytes()?;
+    env_parse::<u32>("START_ATTEMPTS")?;
+    env_parse::<u64>("START_RETRY_DELAY")?;
+    env_parse::<u64>("HEALTH_TIMEOUT")?;
+    env_parse::<u64>("GIT_TIMEOUT")?;
+    default_restart_policy()?;
+
+    req_handler::check_settings()
 }
 
 async fn init_self() {
-    let config_file = [&CONFIGS_DIR, PKG_NAME]
-        .iter()
-        .collect::<PathBuf>()
-        .with_extension("toml");
-    let config = Config::from_file(config_file).await.unwrap();
+    // The package name is always a valid name, we can unwrap safely
+    let config = Config::from_file(config_path(PKG_NAME).await.unwrap())
+        .await
+        .unwrap();
     trace!("Initializing self");
-    if let Err(why) = run_container(&DOCKER, config).await {
+    if let Err(why) = run_container(&DOCKER, &config).await {
         error!("Failed to start self in init stage: {}", why);
     }
 }
 
-async fn init_all() {
+async fn init_container(name: &str) -> bool {
+    match find_config(name).await {
+        Ok(Some(config)) => {
+            trace!("Initializing {}", name);
+            if let Err(why) = sync_container(&DOCKER, &config).await {
+                error!("Failed to start container {} in init stage: {}", name, why);
+                return false;
+            }
+            true
+        }
+        Ok(None) => {
+            error!("No config found for {}", name);
+            false
+        }
+        Err(why) => {
+            error!("Invalid config for {}: {:#}", name, why);
+            false
+        }
+    }
+}
+
+/// Initializes every enabled container, returning whether all of them started
+async fn init_all(docker: &Docker) -> bool {
     trace!("Initializing");
-    let config_files = fs::read_dir(&*CONFIGS_DIR).await;
-    match config_files {
+    let paths = match config_files().await {
+        Ok(paths) => paths,
+        Err(why) => {
+            error!("Error reading configs: {:#?}", why);
+            return false;
+        }
+    };
+
+    let (mut started, mut invalid, mut failed) = (0, 0, 0);
+    let mut configs = Vec::new();
+    for path in &paths {
+        let config = Config::from_file(path)
+            .await
+            .and_then(|config| config.check_branches(&paths).map(|_| config));
+        match config {
+            Ok(config) => configs.extend(config.with_branches()),
+            Err(why) => {
+                error!("Invalid config {:#?}: {}", path, why);
+                invalid += 1;
+            }
+        }
+    }
+    for config in configs {
+        // We need to clone the name here to use it in the error message
+        let name = config.name.clone();
+
+        if !config.enabled {
+            info!("Skipping disabled container {}", name);
+            continue;
+        }
+
+        trace!("Initializing {}", name);
+        match sync_container(docker, &config).await {
+            Ok(()) => started += 1,
+            Err(why) => {
+                error!("Failed to start container {} in init stage: {}", name, why);
+                failed += 1;
+            }
+        }
+    }
+
+    let total = started + invalid + failed;
+    if invalid + failed == 0 {
+        info!("Initialized {}/{} containers", started, total);
+    } else {
+        error!(
+            "Initialized {}/{} containers; {} failed to start, {} invalid",
+            started, total, failed, invalid
+        );
+    }
+
+    invalid + failed == 0
+}
+
+async fn list_containers() {
+    let paths = match config_files().await {
+        Ok(paths) => paths,
+        Err(why) => {
+            error!("Error reading configs: {:#?}", why);
+            return;
+        }
+    };
+
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "URL".to_string(),
+        "RUNNING".to_string(),
+        "IMAGE".to_string(),
+        "STATUS".to_string(),
+    ]];
+    let mut configs = Vec::new();
+    for path in paths {
+        match Config::from_file(&path).await {
+            Ok(config) => configs.extend(config.with_branches()),
+            Err(why) => error!("Invalid config {:#?}: {}", path, why),
+        }
+    }
+    for config in configs {
+        let (running, image, status) = match find_managed_container(&DOCKER, &config.name).await {
+            Ok(Some(c)) => (
+                (c.state.as_deref() == Some("running")).to_string(),
+                c.image.unwrap_or_default(),
+                c.status.unwrap_or_default(),
+            ),
+            Ok(None) => (
+                "false".to_string(),
+                "-".to_string(),
+                "not created".to_string(),
+            ),
+            Err(why) => {
+                error!("Failed to look up container {}: {:#?}", config.name, why);
+                ("?".to_string(), "?".to_string(), "unknown".to_string())
+            }
+        };
+        rows.push([config.name, config.url, running, image, status]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Removes the repos and managed containers left behind by deleted configs.
+/// Without `yes`, only prints what would be removed.
+async fn prune(docker: &Docker, yes: bool) {
+    let paths = match config_files().await {
+        Ok(paths) => paths,
+        Err(why) => {
+            error!("Error reading configs: {:#?}", why);
+            return;
+        }
+    };
+    let mut names = HashSet::new();
+    for path in paths {
+        match Config::from_file(&path).await {
+            Ok(config) => names.extend(config.with_branches().into_iter().map(|c| c.name)),
+            // The containers of its branches are unknown, so they could be removed
+            Err(why) => {
+                error!("Not pruning, invalid config {:#?}: {}", path, why);
+                return;
+            }
+        }
+    }
+
+    let mut repos = Vec::new();
+    match fs::read_dir(&*REPOS_DIR).await {
         Ok(mut entries) => {
-            trace!("Getting directory entries");
             while let Ok(Some(entry)) = entries.next_entry().await {
                 let path = entry.path();
-                if path.is_file() && path.extension().map(|s| s.to_str()).flatten() == Some("toml")
-                {
-                    let config = Config::from_file(entry.path()).await.unwrap();
-                    // We need to clone the name here to use it in the error message
-                    let name = config.name.clone();
-
-                    trace!("Initializing {}", name);
-                    if let Err(why) = run_container(&DOCKER, config).await {
-                        error!("Failed to start container {} in init stage: {}", name, why);
-                    }
-                } else {
-                    trace!("Ignoring directory or non-toml file {:#?}", path);
+                let orphaned = path.is_dir()
+                    && entry
+                        .file_name()
+                        .to_str()
+                        .is_some_and(|name| !name.starts_with('.') && !names.contains(name));
+                if orphaned {
+                    repos.push(path);
                 }
             }
         }
-        Err(why) => error!(
-            "Error reading configs directory {:#?}: {}",
-            *CONFIGS_DIR, why
-        ),
+        Err(why) => error!("Unable to read repos directory {:#?}: {}", *REPOS_DIR, why),
+    }
+
+    let containers: Vec<(String, String)> = match list_managed_containers(docker).await {
+        Ok(containers) => containers
+            .into_iter()
+            .filter_map(|c| {
+                // Takeover containers have no name label and remove themselves
+                let name = c.labels?.remove(NAME_LABEL)?;
+                Some((c.id?, name))
+            })
+            .filter(|(_, name)| !names.contains(name))
+            .collect(),
+        Err(why) => {
+            error!("Failed to list containers: {:#?}", why);
+            Vec::new()
+        }
+    };
+
+    if repos.is_empty() && containers.is_empty() {
+        println!("Nothing to prune");
+        return;
+    }
+    for path in &repos {
+        println!("repo       {}", path.display());
+    }
+    for (id, name) in &containers {
+        println!("container  {} ({})", name, id);
+    }
+    if !yes || DRY_RUN.load(Ordering::Relaxed) {
+        println!("Run with --prune --yes to remove these");
+        return;
+    }
+
+    for path in repos {
+        match fs::remove_dir_all(&path).await {
+            Ok(()) => info!("Removed repo {:#?}", path),
+            Err(why) => error!("Failed to remove repo {:#?}: {}", path, why),
+        }
+    }
+    for (id, name) in containers {
+        match stop_container(docker, &id, None).await {
+            Ok(()) => info!("Removed container {} ({})", name, id),
+            Err(why) => error!("Failed to remove container {}: {:#}", name, why),
+        }
     }
 }
 
-async fn start_server() {
+async fn start_server(tls_acceptor: Option<TlsAcceptor>) {
     let addr = SocketAddr::from(([0, 0, 0, 0], *PORT));
+    // The first self-update received shuts the server down, so there is never
+    // a reason to queue more than one. Tokio has no rendezvous channels.
     let (tx, mut rx) = mpsc::channel::<Config>(1);
     let mut config = None;
-    let server = Server::bind(&addr)
-        .serve(MakeReqHandler { tx })
-        .with_graceful_shutdown(async {
-            config = rx.recv().await;
-        });
 
-    info!("Starting server");
-    if let Err(why) = server.await {
+    match CONFIGS.rescan().await {
+        Ok(report) => {
+            for (name, why) in report.errors {
+                error!("Invalid config {}: {}", name, why);
+            }
+        }
+        Err(why) => error!("Failed to scan configs: {:#?}", why),
+    }
+
+    match config_files().await {
+        Ok(paths) => {
+            for path in paths {
+                let configs = match Config::from_file(&path).await {
+                    Ok(config) => config.with_branches(),
+                    Err(why) => {
+                        trace!("Not polling {:#?}: {:#?}", path, why);
+                        continue;
+                    }
+                };
+                for config in configs {
+                    if let Config {
+                        name,
+                        url,
+                        poll_interval: Some(interval),
+                        ..
+                    } = config
+                    {
+                        info!("Polling {} every {}s", name, interval);
+                        spawn_poller(name, url, Duration::from_secs(interval), tx.clone());
+                    }
+                }
+            }
+        }
+        Err(why) => error!("Error reading configs: {:#?}", why),
+    }
+
+    if let Some(max_starts) = *CRASH_LOOP_STARTS {
+        info!(
+            "Stopping containers started more than {} times within {:?}",
+            max_starts, *CRASH_LOOP_WINDOW
+        );
+        spawn_crash_watcher(max_starts, *CRASH_LOOP_WINDOW);
+    }
+
+    remove_stale_worktrees().await;
+
+    // Resume deploys that were interrupted by a restart
+    match JOURNAL.load().await {
+        Ok(pending) => {
+            for deploy in pending {
+                info!("Resuming interrupted deploy of {}", deploy.name);
+                spawn_update(deploy, tx.clone());
+            }
+        }
+        Err(why) => error!("Failed to load deploy journal: {:#?}", why),
+    }
+
+    tokio::spawn(async {
+        loop {
+            match DOCKER.ping().await {
+                Ok(_) => {
+                    info!("Connected to Docker, ready to deploy");
+                    READY.store(true, Ordering::Relaxed);
+                    break;
+                }
+                Err(why) => {
+                    error!("Failed to reach Docker, retrying: {}", why);
+                    time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+
+    let make_service = MakeReqHandler { tx };
+    let shutdown = async {
+        tokio::select! {
+            cfg = rx.recv() => config = cfg,
+            _ = shutdown_signal() => info!("Shutting down"),
+        }
+    };
+
+    let result = match tls_acceptor {
+        Some(acceptor) => {
+            let incoming = match tls::incoming(acceptor, addr).await {
+                Ok(incoming) => incoming,
+                Err(why) => {
+                    error!("Failed to start TLS listener: {:#?}", why);
+                    return;
+                }
+            };
+
+            info!("Starting server with TLS");
+            Server::builder(accept::from_stream(incoming))
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        None => {
+            info!("Starting server");
+            Server::bind(&addr)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    };
+    if let Err(why) = result {
         error!("Server error: {}", why);
     }
 
+    let running = drain_deploys(*SHUTDOWN_GRACE).await;
+    if running > 0 {
+        error!(
+            "{} deploys still running after {:?}, abandoning them",
+            running, *SHUTDOWN_GRACE
+        );
+    }
+
     // This is executed when we do a self-update
     if let Some(cfg) = config {
-        run_container(&DOCKER, cfg).await.unwrap()
+        self_update(&cfg).await;
+    }
+}
+
+/// Resolves once hermes is asked to stop with SIGTERM or Ctrl+C
+async fn shutdown_signal() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(why) => {
+            error!("Failed to listen for SIGTERM: {}", why);
+            let _ = tokio::signal::ctrl_c().await;
+        }
     }
 }
 
+/// Creates the new hermes container and hands over to a takeover helper.
+///
+/// The new container can't be started from here since this instance still
+/// holds its ports until it exits.
+async fn self_update(config: &Config) {
+    let old_name = [PKG_NAME, "-old"].concat();
+    if stop_container(&DOCKER, &old_name, None).await.is_ok() {
+        info!("Self-update: removed leftover container {}", old_name);
+    }
+
+    // The running instance holds our container name, so move it out of the way
+    let renamed = match rename_container(&DOCKER, PKG_NAME, &old_name).await {
+        Ok(()) => {
+            info!("Self-update: renamed running instance to {}", old_name);
+            true
+        }
+        Err(why) => {
+            info!("Self-update: not renaming running instance: {:#}", why);
+            false
+        }
+    };
+
+    info!("Self-update: creating new {} container", PKG_NAME);
+    if let Err(why) = create_container(&DOCKER, config).await {
+        error!("Self-update failed, unable to create container: {:#?}", why);
+        if renamed {
+            if let Err(why) = rename_container(&DOCKER, &old_name, PKG_NAME).await {
+                error!("Failed to restore name of running instance: {:#?}", why);
+            }
+        }
+        return;
+    }
+
+    match spawn_takeover(&DOCKER, config).await {
+        Ok(id) => info!("Self-update: handed over to takeover container {}", id),
+        Err(why) => error!("Self-update failed, unable to start takeover: {:#?}", why),
+    }
+}
+
+/// Replaces the previous instance with the container created by a self-update
+async fn takeover(docker: &Docker, backoff: Duration) {
+    let old_name = [PKG_NAME, "-old"].concat();
+    info!("Takeover: stopping previous instance {}", old_name);
+    // The previous instance is only removed once the new one is running, so
+    // it can be brought back if the new one fails to start
+    let stopped = match halt_container(docker, &old_name, None).await {
+        Ok(()) => {
+            info!("Takeover: stopped {}", old_name);
+            true
+        }
+        Err(why) => {
+            info!("Takeover: no previous container to stop: {:#}", why);
+            false
+        }
+    };
+
+    if start_with_backoff(docker, PKG_NAME, backoff).await {
+        info!("Takeover: started new {} container", PKG_NAME);
+        if stopped {
+            if let Err(why) = remove_container(docker, &old_name).await {
+                error!("Takeover: failed to remove {}: {:#}", old_name, why);
+            }
+        }
+        return;
+    }
+    if !stopped {
+        error!("Takeover failed, {} was not started", PKG_NAME);
+        return;
+    }
+
+    error!("Takeover failed, restoring previous instance {}", old_name);
+    if let Err(why) = remove_container(docker, PKG_NAME).await {
+        error!(
+            "Takeover: failed to remove new {} container: {:#}",
+            PKG_NAME, why
+        );
+        return;
+    }
+    if let Err(why) = rename_container(docker, &old_name, PKG_NAME).await {
+        error!(
+            "Takeover: failed to restore name of {}: {:#}",
+            old_name, why
+        );
+        return;
+    }
+    if start_with_backoff(docker, PKG_NAME, backoff).await {
+        info!("Takeover: restored previous instance");
+    } else {
+        error!("Takeover failed, no {} instance is running", PKG_NAME);
+    }
+}
+
+/// Tries to start the container `name`, backing off exponentially between
+/// attempts, and returns whether it started
+async fn start_with_backoff(docker: &Docker, name: &str, backoff: Duration) -> bool {
+    let mut delay = backoff;
+    for attempt in 1..=TAKEOVER_ATTEMPTS {
+        // Docker frees the published ports once the old container is stopped, but
+        // an instance running outside of Docker may still be shutting down
+        match start_container(docker, name).await {
+            Ok(()) => return true,
+            Err(why) => info!(
+                "Takeover: attempt {}/{} to start {} failed: {:#}",
+                attempt, TAKEOVER_ATTEMPTS, name, why
+            ),
+        }
+        if attempt < TAKEOVER_ATTEMPTS {
+            time::sleep(delay + jitter(delay)).await;
+            delay = (delay * 2).min(TAKEOVER_MAX_BACKOFF);
+        }
+    }
+    false
+}
+
+/// Up to a quarter of `delay`, so retries of several hosts don't line up
+fn jitter(delay: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    delay.mul_f64(f64::from(nanos) / 1e9 / 4.0)
+}
+
 enum Init {
     Server,
     AllContainers,
+    Container(String),
     Itself,
+    List,
+    Prune,
+    Takeover,
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    env_logger::init();
+    logging::init();
 
-    let mut args = env::args();
+    let mut args = env::args().skip(1).peekable();
     let mut init = Init::Server;
+    let mut dry_run = env_flag("DRY_RUN");
+    let mut yes = false;
     while let Some(arg) = args.next() {
-        if arg == "--init" {
-            init = if args.next() == Some("all".to_string()) {
-                Init::AllContainers
-            } else {
-                Init::Itself
-            };
-
-            break;
+        match arg.as_str() {
+            "--init" => {
+                init = match args.next_if(|arg| !arg.starts_with("--")) {
+                    Some(name) if name == "all" => Init::AllContainers,
+                    Some(name) => Init::Container(name),
+                    None => Init::Itself,
+                };
+            }
+            "--dry-run" => dry_run = true,
+            "--list" => init = Init::List,
+            "--prune" => init = Init::Prune,
+            "--yes" => yes = true,
+            "--takeover" => init = Init::Takeover,
+            // The schema doesn't depend on anything else, so it is printed right away
+            "--schema" => {
+                // Serializing a schema can't fail, we can unwrap safely
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&config::schema()).unwrap()
+                );
+                return;
+            }
+            // The directories are read lazily from the environment, so the flags
+            // override the env vars as long as they're set before first use
+            flag @ ("--configs-dir" | "--repos-dir") => match args.next() {
+                Some(dir) => {
+                    let var = if flag == "--configs-dir" {
+                        "CONFIGS_DIR"
+                    } else {
+                        "REPOS_DIR"
+                    };
+                    env::set_var(var, dir);
+                }
+                None => {
+                    error!("Expected a directory after {}", flag);
+                    process::exit(1);
+                }
+            },
+            _ => {}
         }
     }
 
+    if dry_run {
+        info!("Running in dry-run mode, no changes will be made");
+        DRY_RUN.store(true, Ordering::Relaxed);
+    }
+
+    if let Err(why) = check_settings() {
+        error!("{:#}", why);
+        process::exit(1);
+    }
+
     let configs_dir = Path::new(&*CONFIGS_DIR);
     if !configs_dir.is_dir() {
         error!("Invalid configs directory {:#?}", configs_dir);
-        return;
+        process::exit(1);
     }
 
     match init {
         Init::Itself => init_self().await,
-        Init::AllContainers => init_all().await,
+        Init::AllContainers => {
+            // Lets supervisors notice containers that didn't come up
+            if !init_all(&DOCKER).await {
+                process::exit(1);
+            }
+        }
+        Init::Container(name) => {
+            if !init_container(&name).await {
+                process::exit(1);
+            }
+        }
+        Init::List => list_containers().await,
+        Init::Prune => prune(&DOCKER, yes).await,
+        Init::Takeover => takeover(&DOCKER, TAKEOVER_BACKOFF).await,
         Init::Server => {
             // Validate repos dir
             // We only validate it here because it isn't
@@ -145,10 +758,345 @@ async fn main() {
             let repos_dir = Path::new(&*REPOS_DIR);
             if !repos_dir.is_dir() {
                 error!("Invalid repos directory {:#?}", repos_dir);
-                return;
+                process::exit(1);
+            }
+
+            if let Err(why) = load_secret() {
+                error!("Failed to load secret token: {:#}", why);
+                process::exit(1);
             }
 
-            start_server().await;
+            // Both or neither of the TLS variables must be set
+            let tls_acceptor = match (&*TLS_CERT, &*TLS_KEY) {
+                (Some(cert), Some(key)) => {
+                    match tls::load_acceptor(Path::new(cert), Path::new(key)) {
+                        Ok(acceptor) => Some(acceptor),
+                        Err(why) => {
+                            error!("Failed to load TLS certificate and key: {:#?}", why);
+                            process::exit(1);
+                        }
+                    }
+                }
+                (None, None) => None,
+                _ => {
+                    error!("TLS_CERT and TLS_KEY must be set together");
+                    process::exit(1);
+                }
+            };
+
+            start_server(tls_acceptor).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::utils::docker::tests::{json, message, mock_docker};
+    use bollard::models::Image;
+    use hyper::{
+        header::CONTENT_TYPE, server::conn::Http, service::service_fn, Body, Method, Request,
+        Response, StatusCode,
+    };
+    use std::{
+        convert::Infallible,
+        env, fs,
+        os::unix,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, Ordering::SeqCst},
+            Arc, Mutex, Once, PoisonError,
+        },
+        time::Duration,
+    };
+    use tokio::{net::UnixListener, runtime::Runtime};
+
+    /// Requests made to the Docker daemon of the test environment, as `METHOD /path`
+    pub static DOCKER_REQUESTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    /// Held by tests changing the settings in the environment, which is shared
+    /// by every test
+    pub static SETTINGS: Mutex<()> = Mutex::new(());
+    /// Messages logged at the info level or above since the test environment was set up
+    pub static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CapturedLogs;
+
+    impl log::Log for CapturedLogs {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Info
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                LOGS.lock().unwrap().push(record.args().to_string());
+            }
         }
+
+        fn flush(&self) {}
+    }
+
+    /// Serves a Docker daemon on `socket` that knows no containers or images
+    fn docker_daemon(socket: PathBuf) {
+        let _ = fs::remove_file(&socket);
+        let listener = unix::net::UnixListener::bind(&socket).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            Runtime::new().unwrap().block_on(async move {
+                let listener = UnixListener::from_std(listener).unwrap();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let service = service_fn(|req: Request<Body>| async move {
+                        let request = format!("{} {}", req.method(), req.uri().path());
+                        let listing = req.uri().path().ends_with("/containers/json");
+                        let ping = req.uri().path().ends_with("/_ping");
+                        DOCKER_REQUESTS.lock().unwrap().push(request);
+                        let response = Response::builder().header(CONTENT_TYPE, "application/json");
+                        let response = if listing {
+                            response.body(Body::from("[]"))
+                        } else if ping {
+                            response.body(Body::from("OK"))
+                        } else {
+                            response
+                                .status(StatusCode::NOT_FOUND)
+                                .body(Body::from(r#"{"message": "not found"}"#))
+                        };
+                        Ok::<_, Infallible>(response.unwrap())
+                    });
+                    tokio::spawn(Http::new().serve_connection(stream, service));
+                }
+            })
+        });
+    }
+
+    /// Points the settings hermes reads from the environment at a scratch
+    /// directory with a config for `app`, and Docker at [`docker_daemon`].
+    /// They are only read once, so every test relying on them goes through
+    /// here first.
+    pub fn test_env() -> PathBuf {
+        static INIT: Once = Once::new();
+        let dir = env::temp_dir().join(format!("hermes-test-env-{}", std::process::id()));
+        INIT.call_once(|| {
+            for sub in ["configs", "repos", "state"] {
+                fs::create_dir_all(dir.join(sub)).unwrap();
+                env::set_var([&sub.to_uppercase(), "_DIR"].concat(), dir.join(sub));
+            }
+            fs::write(dir.join("configs/app.toml"), "url = \"/dev/null\"\n").unwrap();
+            fs::write(
+                dir.join("configs/off.toml"),
+                "url = \"/dev/null\"\nenabled = false\n",
+            )
+            .unwrap();
+            fs::write(
+                dir.join("configs/mono.toml"),
+                "url = \"/dev/null\"\npaths = [\"services/api/**\"]\n",
+            )
+            .unwrap();
+            log::set_logger(&CapturedLogs).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+            env::set_var("SECRET_TOKEN", "secret");
+            env::set_var("SSH_KEY", dir.join("id_ed25519"));
+            docker_daemon(dir.join("docker.sock"));
+            env::set_var(
+                "DOCKER_HOST",
+                ["unix://", dir.join("docker.sock").to_str().unwrap()].concat(),
+            );
+        });
+        dir
+    }
+
+    #[test]
+    fn malformed_settings_fail_the_startup_check() {
+        test_env();
+        // Settings are read once, so reading them all now keeps the malformed
+        // values away from tests running meanwhile
+        lazy_static::initialize(&super::HISTORY);
+        lazy_static::initialize(&super::SHUTDOWN_GRACE);
+        lazy_static::initialize(&super::PORT);
+        lazy_static::initialize(&super::HEALTH_TIMEOUT);
+        lazy_static::initialize(&super::CRASH_LOOP_STARTS);
+        lazy_static::initialize(&super::CRASH_LOOP_WINDOW);
+        lazy_static::initialize(&super::OUTPUT_TAIL_BYTES);
+        lazy_static::initialize(&super::DEFAULT_RESTART_POLICY);
+        lazy_static::initialize(&super::START_ATTEMPTS);
+        lazy_static::initialize(&super::START_RETRY_DELAY);
+        lazy_static::initialize(&super::GIT_TIMEOUT);
+        crate::req_handler::tests::load_settings();
+        let _settings = SETTINGS.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let overflowing = (usize::MAX / 1024 + 1).to_string();
+        for (key, value) in [
+            ("HISTORY_SIZE", "-1"),
+            ("SHUTDOWN_GRACE", "30s"),
+            ("PORT", "80a0"),
+            ("CRASH_LOOP_STARTS", "three"),
+            ("CRASH_LOOP_WINDOW", "5m"),
+            ("LOG_MAX_KB", "64k"),
+            ("LOG_MAX_KB", &overflowing),
+            ("START_ATTEMPTS", "0x3"),
+            ("START_RETRY_DELAY", "2.5"),
+            ("HEALTH_TIMEOUT", "1m"),
+            ("GIT_TIMEOUT", "-5"),
+            ("DEFAULT_RESTART_POLICY", "sometimes"),
+            ("MAX_CONCURRENT_BUILDS", "many"),
+            ("MAX_BODY_BYTES", "5MB"),
+            ("DEPLOY_TIMEOUT", "20m"),
+            ("REPOS_MAX_BYTES", "1G"),
+            ("IMAGE_KEEP", "all"),
+            ("IMAGE_MAX_AGE", "a week"),
+            ("WEBHOOK_ALLOWED_IPS", "140.82.112.0/33"),
+            ("TRUSTED_PROXIES", "10.0.0.300"),
+        ] {
+            env::set_var(key, value);
+            let checked = super::check_settings();
+            env::remove_var(key);
+            let why = checked.expect_err(key).to_string();
+            assert!(why.contains(key), "{}", why);
+        }
+    }
+
+    #[tokio::test]
+    async fn initializing_an_unknown_container_fails() {
+        test_env();
+        assert!(!super::init_container("missing").await);
+        assert!(!super::init_container("../app").await);
+    }
+
+    #[tokio::test]
+    async fn disabled_containers_are_skipped_on_init() {
+        test_env();
+        super::init_all(&super::DOCKER).await;
+        let requests = DOCKER_REQUESTS.lock().unwrap();
+        assert!(requests.iter().any(|req| req.contains("/images/app/")));
+        assert!(!requests.iter().any(|req| req.contains("/off")));
+    }
+
+    #[tokio::test]
+    async fn init_reports_which_containers_failed() {
+        let dir = test_env();
+        fs::write(dir.join("configs/broken.toml"), "url = 1\n").unwrap();
+        let docker = mock_docker(|method, path, _| match (method, path) {
+            (_, path) if path.contains("mono") => {
+                message(StatusCode::INTERNAL_SERVER_ERROR, "no space left on device")
+            }
+            (&Method::GET, "/containers/json") => (StatusCode::OK, "[]".to_string()),
+            (&Method::GET, path) if path.starts_with("/images/") => json(
+                StatusCode::OK,
+                &Image {
+                    id: "sha256:1234".to_string(),
+                    ..Default::default()
+                },
+            ),
+            (&Method::POST, "/containers/create") => (
+                StatusCode::CREATED,
+                r#"{"Id": "4567cdef", "Warnings": []}"#.to_string(),
+            ),
+            _ => (StatusCode::NO_CONTENT, String::new()),
+        });
+        let initialized = super::init_all(&docker).await;
+        fs::remove_file(dir.join("configs/broken.toml")).unwrap();
+
+        assert!(!initialized);
+        let logs = LOGS.lock().unwrap().clone();
+        assert!(
+            logs.iter().any(|log| log.starts_with("Initialized ")
+                && log.ends_with("containers; 1 failed to start, 1 invalid")),
+            "{:#?}",
+            logs
+        );
+        assert!(logs.iter().any(|log| log.contains("broken.toml")));
+    }
+
+    #[tokio::test]
+    async fn failed_takeovers_restore_the_previous_instance() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let restored = Arc::new(AtomicBool::new(false));
+        let docker = mock_docker({
+            let (requests, restored) = (requests.clone(), restored.clone());
+            move |method, path, _| {
+                requests
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", method, path));
+                match (method, path) {
+                    // The new instance never comes up
+                    (&Method::POST, "/containers/hermes/start") if !restored.load(SeqCst) => {
+                        message(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "port is already allocated",
+                        )
+                    }
+                    (&Method::POST, "/containers/hermes-old/rename") => {
+                        restored.store(true, SeqCst);
+                        (StatusCode::NO_CONTENT, String::new())
+                    }
+                    _ => (StatusCode::NO_CONTENT, String::new()),
+                }
+            }
+        });
+        super::takeover(&docker, Duration::from_millis(1)).await;
+
+        let requests = requests.lock().unwrap();
+        let starts = "POST /containers/hermes/start".to_string();
+        assert_eq!(
+            requests.iter().filter(|req| **req == starts).count(),
+            super::TAKEOVER_ATTEMPTS as usize + 1
+        );
+        assert_eq!(
+            requests[requests.len() - 3..],
+            [
+                "DELETE /containers/hermes".to_string(),
+                "POST /containers/hermes-old/rename".to_string(),
+                starts,
+            ]
+        );
+        assert_eq!(requests[0], "POST /containers/hermes-old/stop");
+        assert!(!requests.contains(&"DELETE /containers/hermes-old".to_string()));
+    }
+
+    #[tokio::test]
+    async fn prunes_remove_what_deleted_configs_left_behind() {
+        test_env();
+        let repos = PathBuf::from(&*super::REPOS_DIR);
+        for repo in ["mono", "pruned"] {
+            fs::create_dir_all(repos.join(repo)).unwrap();
+        }
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let docker = mock_docker({
+            let requests = requests.clone();
+            move |method, path, _| {
+                let container = |id: &str, name: &str| {
+                    serde_json::json!({
+                        "Id": id,
+                        "Labels": { "hermes.managed": "true", "hermes.name": name },
+                    })
+                };
+                match (method, path) {
+                    (&Method::GET, "/containers/json") => (
+                        StatusCode::OK,
+                        serde_json::json!([container("1111", "mono"), container("2222", "pruned")])
+                            .to_string(),
+                    ),
+                    _ => {
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", method, path));
+                        (StatusCode::NO_CONTENT, String::new())
+                    }
+                }
+            }
+        });
+
+        // Nothing is removed without --yes
+        super::prune(&docker, false).await;
+        assert!(repos.join("pruned").is_dir());
+        assert!(requests.lock().unwrap().is_empty());
+
+        super::prune(&docker, true).await;
+        assert!(repos.join("mono").is_dir());
+        assert!(!repos.join("pruned").exists());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            ["POST /containers/2222/stop", "DELETE /containers/2222"]
+        );
     }
 }
@@ -0,0 +1,530 @@
+use hmac_sha1_compact::HMAC as HmacSha1;
+use hmac_sha256::HMAC;
+use hyper::HeaderMap;
+use serde::{de, Deserialize, Serialize};
+
+/// The services hermes accepts webhooks from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    GitHub,
+    Bitbucket,
+}
+
+/// A push, as far as hermes is concerned
+pub struct Push {
+    pub name: String,
+    pub repo_url: String,
+    pub commit: Option<String>,
+    /// The full name of the pushed ref, e.g. `refs/tags/v1.0.0`
+    pub git_ref: Option<String>,
+    /// Files added, modified or removed by the push, if the payload lists them
+    pub changed_files: Option<Vec<String>>,
+    /// The repo as `owner/name`
+    pub full_name: Option<String>,
+    /// Whether CI passed, for events that report it
+    pub ci_passed: Option<bool>,
+}
+
+impl Provider {
+    /// Detects the provider that sent a webhook from its event header
+    pub fn detect(headers: &HeaderMap) -> Option<Self> {
+        if headers.contains_key("X-GitHub-Event") {
+            Some(Provider::GitHub)
+        } else if headers.contains_key("X-Event-Key") {
+            Some(Provider::Bitbucket)
+        } else {
+            None
+        }
+    }
+
+    /// The webhook event, with Bitbucket pushes and commit statuses named
+    /// `push` and `status` like GitHub's
+    pub fn event(&self, headers: &HeaderMap) -> Option<String> {
+        match self {
+            Provider::GitHub => header(headers, "X-GitHub-Event"),
+            Provider::Bitbucket => header(headers, "X-Event-Key").map(|event| {
+                match event.as_str() {
+                    // Cloud and Server respectively
+                    "repo:push" | "repo:refs_changed" => "push".to_string(),
+                    "repo:commit_status_created" | "repo:commit_status_updated" => {
+                        "status".to_string()
+                    }
+                    _ => event,
+                }
+            }),
+        }
+    }
+
+    /// The ID of the delivery, which stays the same when it is retried
+    pub fn delivery(&self, headers: &HeaderMap) -> Option<String> {
+        match self {
+            Provider::GitHub => header(headers, "X-GitHub-Delivery"),
+            Provider::Bitbucket => header(headers, "X-Request-UUID"),
+        }
+    }
+
+    pub fn signature(&self, headers: &HeaderMap) -> Option<Signature> {
+        match self {
+            // SHA-1 signatures are only sent by older webhooks, so SHA-256 is preferred
+            Provider::GitHub => header(headers, "X-Hub-Signature-256")
+                .map(Signature::Sha256)
+                .or_else(|| header(headers, "X-Hub-Signature").map(Signature::Sha1)),
+            // Bitbucket sends a SHA-256 signature under the older GitHub header
+            Provider::Bitbucket => header(headers, "X-Hub-Signature").map(Signature::Sha256),
+        }
+    }
+
+    pub fn parse(&self, body: &str) -> serde_json::Result<Push> {
+        match self {
+            Provider::GitHub => {
+                let payload: GitHubPayload = serde_json::from_str(body)?;
+                // Release events name their tag instead of carrying a ref
+                let mut git_ref = payload
+                    .release
+                    .map(|release| ["refs/tags/", &release.tag_name].concat())
+                    .or(payload.git_ref);
+                let mut commit = payload.after;
+                let mut ci_passed = None;
+                // CI events report on a commit, which is deployed from the first
+                // branch it is the head of
+                if let Some(suite) = payload.check_suite {
+                    commit = Some(suite.head_sha);
+                    git_ref = suite
+                        .head_branch
+                        .map(|branch| ["refs/heads/", &branch].concat());
+                    ci_passed = Some(suite.conclusion.as_deref() == Some("success"));
+                } else if let (Some(sha), Some(state)) = (payload.sha, payload.state) {
+                    commit = Some(sha);
+                    git_ref = payload
+                        .branches
+                        .and_then(|branches| branches.into_iter().next())
+                        .map(|branch| ["refs/heads/", &branch.name].concat());
+                    ci_passed = Some(state == "success");
+                }
+                // Tag pushes and branch deletions come with no commits at all
+                let commits = payload.commits.filter(|commits| !commits.is_empty());
+                let changed_files = commits.map(|commits| {
+                    commits
+                        .into_iter()
+                        .flat_map(|c| [c.added, c.modified, c.removed])
+                        .flatten()
+                        .collect()
+                });
+                Ok(Push {
+                    name: payload.repository.name,
+                    repo_url: payload.repository.ssh_url,
+                    commit,
+                    git_ref,
+                    changed_files,
+                    full_name: payload.repository.full_name,
+                    ci_passed,
+                })
+            }
+            Provider::Bitbucket => {
+                let payload: BitbucketPayload = serde_json::from_str(body)?;
+                let repo = payload.repository;
+                // `name` is a display name, the slug is what the repo is cloned as
+                let name = repo
+                    .slug
+                    .clone()
+                    .or_else(|| Some(repo.full_name.as_ref()?.rsplit('/').next()?.to_string()))
+                    .ok_or_else(|| de::Error::missing_field("slug"))?;
+                // Bitbucket Cloud doesn't include clone links in its payloads
+                let repo_url = repo
+                    .links
+                    .and_then(|links| links.clone)
+                    .and_then(|links| links.into_iter().find(|link| link.name == "ssh"))
+                    .map(|link| link.href)
+                    .or_else(|| {
+                        Some(["git@bitbucket.org:", repo.full_name.as_ref()?, ".git"].concat())
+                    })
+                    .ok_or_else(|| de::Error::missing_field("links"))?;
+                let (mut commit, mut git_ref) = match (payload.push, payload.changes) {
+                    (Some(push), _) => {
+                        let new = push.changes.into_iter().find_map(|change| change.new);
+                        let git_ref = new.as_ref().and_then(|new| {
+                            let prefix = match new.kind.as_deref()? {
+                                "tag" => "refs/tags/",
+                                _ => "refs/heads/",
+                            };
+                            Some([prefix, new.name.as_deref()?].concat())
+                        });
+                        (new.map(|new| new.target.hash), git_ref)
+                    }
+                    (None, Some(changes)) => match changes.into_iter().next() {
+                        Some(change) => (Some(change.to_hash), change.ref_id),
+                        None => (None, None),
+                    },
+                    (None, None) => (None, None),
+                };
+                let mut ci_passed = None;
+                // Commit status events report on a commit of the branch they name
+                if let Some(status) = payload.commit_status {
+                    commit = Some(status.commit.hash);
+                    git_ref = status
+                        .refname
+                        .map(|branch| ["refs/heads/", &branch].concat());
+                    ci_passed = Some(status.state == "SUCCESSFUL");
+                }
+
+                // Bitbucket payloads don't list the changed files
+                Ok(Push {
+                    name,
+                    repo_url,
+                    commit,
+                    git_ref,
+                    changed_files: None,
+                    full_name: repo.full_name,
+                    ci_passed,
+                })
+            }
+        }
+    }
+}
+
+fn header(headers: &HeaderMap, key: &str) -> Option<String> {
+    Some(headers.get(key)?.to_str().ok()?.to_string())
+}
+
+/// A webhook signature header of the form `<algorithm>=<hex digest>`
+pub enum Signature {
+    Sha256(String),
+    Sha1(String),
+}
+
+impl Signature {
+    /// Checks the signature against the HMAC of `body` keyed with `secret`
+    pub fn verify(&self, body: &[u8], secret: &[u8]) -> bool {
+        let (header, prefix, mac) = match self {
+            Signature::Sha256(header) => (header, "sha256=", HMAC::mac(body, secret).to_vec()),
+            Signature::Sha1(header) => (header, "sha1=", HmacSha1::mac(body, secret).to_vec()),
+        };
+        header
+            .strip_prefix(prefix)
+            .map(|sig| constant_time_eq(sig.as_bytes(), hex::encode(mac).as_bytes()))
+            .unwrap_or(false)
+    }
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The parts of a GitHub push event hermes cares about
+#[derive(Deserialize)]
+struct GitHubPayload {
+    repository: GitHubRepository,
+    after: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    release: Option<GitHubRelease>,
+    commits: Option<Vec<GitHubCommit>>,
+    check_suite: Option<GitHubCheckSuite>,
+    // Sent by `status` events
+    sha: Option<String>,
+    state: Option<String>,
+    branches: Option<Vec<GitHubBranch>>,
+}
+
+#[derive(Deserialize)]
+struct GitHubBranch {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubCheckSuite {
+    head_sha: String,
+    head_branch: Option<String>,
+    conclusion: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubCommit {
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    modified: Vec<String>,
+    #[serde(default)]
+    removed: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubRelease {
+    tag_name: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubRepository {
+    name: String,
+    ssh_url: String,
+    full_name: Option<String>,
+}
+
+/// The parts of a Bitbucket Cloud (`push`) or Server (`changes`) push event,
+/// or of a Cloud commit status event, hermes cares about
+#[derive(Deserialize)]
+struct BitbucketPayload {
+    repository: BitbucketRepository,
+    push: Option<BitbucketPush>,
+    changes: Option<Vec<BitbucketServerChange>>,
+    commit_status: Option<BitbucketCommitStatus>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketRepository {
+    full_name: Option<String>,
+    slug: Option<String>,
+    links: Option<BitbucketLinks>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketLinks {
+    clone: Option<Vec<BitbucketCloneLink>>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketCloneLink {
+    name: String,
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketPush {
+    changes: Vec<BitbucketChange>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketChange {
+    new: Option<BitbucketRef>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketRef {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    name: Option<String>,
+    target: BitbucketTarget,
+}
+
+#[derive(Deserialize)]
+struct BitbucketTarget {
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketCommitStatus {
+    state: String,
+    refname: Option<String>,
+    commit: BitbucketTarget,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitbucketServerChange {
+    to_hash: String,
+    ref_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_pushes_parse() {
+        let body = r#"{"repository": {"name": "app", "ssh_url": "git@github.com:o/app.git"}, "after": "abc"}"#;
+        let push = Provider::GitHub.parse(body).unwrap();
+        assert_eq!(push.name, "app");
+        assert_eq!(push.repo_url, "git@github.com:o/app.git");
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+        assert_eq!(push.git_ref, None);
+
+        let body =
+            r#"{"repository": {"name": "app", "ssh_url": "/dev/null"}, "ref": "refs/tags/v1.0.0"}"#;
+        let push = Provider::GitHub.parse(body).unwrap();
+        assert_eq!(push.git_ref.as_deref(), Some("refs/tags/v1.0.0"));
+    }
+
+    #[test]
+    fn github_pushes_list_their_changed_files() {
+        let body = r#"{"repository": {"name": "app", "ssh_url": "/dev/null"}, "commits": [{"added": ["a"], "modified": ["b"]}, {"removed": ["c"]}]}"#;
+        let push = Provider::GitHub.parse(body).unwrap();
+        assert_eq!(
+            push.changed_files,
+            Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+
+        // Tag pushes come without commits, which isn't the same as changing nothing
+        let body = r#"{"repository": {"name": "app", "ssh_url": "/dev/null"}, "ref": "refs/tags/v1.0.0", "commits": []}"#;
+        assert_eq!(Provider::GitHub.parse(body).unwrap().changed_files, None);
+    }
+
+    #[test]
+    fn github_releases_deploy_their_tag() {
+        let body = r#"{"repository": {"name": "app", "ssh_url": "/dev/null"}, "action": "published", "release": {"tag_name": "v1.2.0"}}"#;
+        let push = Provider::GitHub.parse(body).unwrap();
+        assert_eq!(push.git_ref.as_deref(), Some("refs/tags/v1.2.0"));
+        assert_eq!(push.commit, None);
+    }
+
+    #[test]
+    fn bitbucket_cloud_pushes_parse() {
+        let body = r#"{
+            "repository": {"full_name": "team/my-app", "links": {}},
+            "push": {"changes": [{"new": {"type": "branch", "name": "main", "target": {"hash": "abc"}}}]}
+        }"#;
+        let push = Provider::Bitbucket.parse(body).unwrap();
+        assert_eq!(push.name, "my-app");
+        assert_eq!(push.repo_url, "git@bitbucket.org:team/my-app.git");
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+        assert_eq!(push.git_ref.as_deref(), Some("refs/heads/main"));
+
+        let body = r#"{
+            "repository": {"full_name": "team/my-app"},
+            "push": {"changes": [{"new": {"type": "tag", "name": "v1", "target": {"hash": "def"}}}]}
+        }"#;
+        let push = Provider::Bitbucket.parse(body).unwrap();
+        assert_eq!(push.git_ref.as_deref(), Some("refs/tags/v1"));
+
+        // Branch deletions have no new ref
+        let body =
+            r#"{"repository": {"full_name": "team/my-app"}, "push": {"changes": [{"new": null}]}}"#;
+        let push = Provider::Bitbucket.parse(body).unwrap();
+        assert_eq!((push.commit, push.git_ref), (None, None));
+    }
+
+    #[test]
+    fn bitbucket_commit_statuses_pass_only_when_successful() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Event-Key", "repo:commit_status_updated".parse().unwrap());
+        assert_eq!(
+            Provider::Bitbucket.event(&headers).as_deref(),
+            Some("status")
+        );
+
+        let status = |state: &str| {
+            let body = format!(
+                r#"{{
+                    "repository": {{"full_name": "team/my-app"}},
+                    "commit_status": {{"state": "{}", "refname": "main", "commit": {{"hash": "abc"}}}}
+                }}"#,
+                state
+            );
+            Provider::Bitbucket.parse(&body).unwrap()
+        };
+        let push = status("SUCCESSFUL");
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+        assert_eq!(push.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(push.ci_passed, Some(true));
+        for state in ["INPROGRESS", "FAILED", "STOPPED"] {
+            assert_eq!(status(state).ci_passed, Some(false));
+        }
+    }
+
+    #[test]
+    fn bitbucket_server_pushes_parse() {
+        let body = r#"{
+            "repository": {
+                "slug": "my-app",
+                "links": {"clone": [
+                    {"name": "http", "href": "https://bitbucket.example.com/scm/team/my-app.git"},
+                    {"name": "ssh", "href": "ssh://git@bitbucket.example.com:7999/team/my-app.git"}
+                ]}
+            },
+            "changes": [{"toHash": "abc", "refId": "refs/heads/main"}]
+        }"#;
+        let push = Provider::Bitbucket.parse(body).unwrap();
+        assert_eq!(push.name, "my-app");
+        assert_eq!(
+            push.repo_url,
+            "ssh://git@bitbucket.example.com:7999/team/my-app.git"
+        );
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+        assert_eq!(push.git_ref.as_deref(), Some("refs/heads/main"));
+
+        // Without a slug or full name there's nothing to name the repo by
+        let body = r#"{"repository": {}, "changes": []}"#;
+        assert!(Provider::Bitbucket.parse(body).is_err());
+    }
+
+    #[test]
+    fn providers_are_detected_from_their_event_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Provider::detect(&headers), None);
+        headers.insert("X-Event-Key", "repo:push".parse().unwrap());
+        assert_eq!(Provider::detect(&headers), Some(Provider::Bitbucket));
+        headers.insert("X-GitHub-Event", "push".parse().unwrap());
+        assert_eq!(Provider::detect(&headers), Some(Provider::GitHub));
+    }
+
+    #[test]
+    fn signatures_verify_against_the_body_hmac() {
+        let (body, secret) = (b"{}".as_slice(), b"secret".as_slice());
+        let sha256 = ["sha256=", &hex::encode(HMAC::mac(body, secret))].concat();
+        let sha1 = ["sha1=", &hex::encode(HmacSha1::mac(body, secret))].concat();
+
+        assert!(Signature::Sha256(sha256.clone()).verify(body, secret));
+        assert!(Signature::Sha1(sha1).verify(body, secret));
+        assert!(!Signature::Sha256(sha256.clone()).verify(b"{ }", secret));
+        assert!(!Signature::Sha256(sha256.clone()).verify(body, b"other"));
+        // The algorithm has to match the header it came in
+        assert!(!Signature::Sha1(sha256.replacen("sha256", "sha1", 1)).verify(body, secret));
+        assert!(!Signature::Sha256(sha256[7..].to_string()).verify(body, secret));
+    }
+
+    const REPOSITORY: &str = r#""repository": {"name": "app", "ssh_url": "git@github.com:o/app.git", "full_name": "o/app"}"#;
+
+    #[test]
+    fn github_status_events_report_their_commit() {
+        let body = format!(
+            r#"{{{}, "sha": "abc", "state": "success", "branches": [{{"name": "main"}}, {{"name": "dev"}}]}}"#,
+            REPOSITORY
+        );
+        let push = Provider::GitHub.parse(&body).unwrap();
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+        assert_eq!(push.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(push.full_name.as_deref(), Some("o/app"));
+        assert_eq!(push.ci_passed, Some(true));
+
+        for state in ["pending", "failure", "error"] {
+            let body = format!(r#"{{{}, "sha": "abc", "state": "{}"}}"#, REPOSITORY, state);
+            assert_eq!(
+                Provider::GitHub.parse(&body).unwrap().ci_passed,
+                Some(false)
+            );
+        }
+    }
+
+    #[test]
+    fn github_check_suites_pass_only_on_success() {
+        let body = format!(
+            r#"{{{}, "check_suite": {{"head_sha": "abc", "head_branch": "main", "conclusion": "success"}}}}"#,
+            REPOSITORY
+        );
+        let push = Provider::GitHub.parse(&body).unwrap();
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+        assert_eq!(push.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(push.ci_passed, Some(true));
+
+        // Suites that are still running have no conclusion yet
+        let body = format!(
+            r#"{{{}, "check_suite": {{"head_sha": "abc", "head_branch": "main", "conclusion": null}}}}"#,
+            REPOSITORY
+        );
+        assert_eq!(
+            Provider::GitHub.parse(&body).unwrap().ci_passed,
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn github_pushes_leave_ci_status_unknown() {
+        let body = format!(
+            r#"{{{}, "ref": "refs/heads/main", "after": "abc", "commits": [{{"modified": ["a"]}}]}}"#,
+            REPOSITORY
+        );
+        let push = Provider::GitHub.parse(&body).unwrap();
+        assert_eq!(push.ci_passed, None);
+        assert_eq!(push.commit.as_deref(), Some("abc"));
+    }
+}
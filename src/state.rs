@@ -0,0 +1,562 @@
+use crate::provider::Provider;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::Mutex as StdMutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use uuid::Uuid;
+
+/// A deploy that was requested but hasn't finished yet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingDeploy {
+    // Entries journaled before deploys had IDs get a fresh one
+    #[serde(default = "new_deploy_id")]
+    pub id: String,
+    pub name: String,
+    pub repo_url: String,
+    pub commit: Option<String>,
+    #[serde(default)]
+    pub git_ref: Option<String>,
+    /// The ID of the webhook delivery that triggered the deploy
+    #[serde(default)]
+    pub delivery: Option<String>,
+    /// Deploys exactly `commit` instead of the head of its ref, for commits
+    /// whose CI status was checked
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+impl PendingDeploy {
+    pub fn new(
+        name: String,
+        repo_url: String,
+        commit: Option<String>,
+        git_ref: Option<String>,
+        delivery: Option<String>,
+    ) -> Self {
+        PendingDeploy {
+            id: new_deploy_id(),
+            name,
+            repo_url,
+            commit,
+            git_ref,
+            delivery,
+            pinned: false,
+        }
+    }
+}
+
+pub fn new_deploy_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Where a deploy is at, as reported by `/status/{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DeployState {
+    Queued,
+    Running,
+    Succeeded,
+    Skipped { reason: String },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployStatus {
+    pub name: String,
+    pub commit: Option<String>,
+    #[serde(flatten)]
+    pub state: DeployState,
+}
+
+/// Statuses of the most recent deploys, keyed by deploy ID
+pub struct StatusMap {
+    capacity: usize,
+    entries: StdMutex<VecDeque<(String, DeployStatus)>>,
+}
+
+impl StatusMap {
+    pub fn new(capacity: usize) -> Self {
+        StatusMap {
+            capacity,
+            entries: StdMutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn insert(&self, deploy: &PendingDeploy, state: DeployState) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        let status = DeployStatus {
+            name: deploy.name.clone(),
+            commit: deploy.commit.clone(),
+            state,
+        };
+        entries.push_back((deploy.id.clone(), status));
+    }
+
+    pub fn set_state(&self, id: &str, state: DeployState) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, status)) = entries.iter_mut().find(|(entry, _)| entry == id) {
+            status.state = state;
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<DeployStatus> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .find(|(entry, _)| entry == id)
+            .map(|(_, status)| status.clone())
+    }
+}
+
+/// A step of a deploy, as streamed by `/events`
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployEvent {
+    pub name: String,
+    /// `queued`, `fetching`, `building`, `starting`, `self_updating`, or the
+    /// final `succeeded`, `skipped` or `failed`
+    pub phase: &'static str,
+    /// Why the deploy was skipped or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// A webhook request, as listed by `/deliveries`
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryRecord {
+    /// Seconds since the Unix epoch
+    pub received_at: u64,
+    pub provider: Option<Provider>,
+    pub event: Option<String>,
+    /// The ID the provider gave the delivery
+    pub id: Option<String>,
+    pub name: Option<String>,
+    /// Unset if the request was rejected before its signature was checked
+    pub signature_valid: Option<bool>,
+    pub status: u16,
+    /// What was done about it, e.g. `deploying` or the reason it was rejected
+    pub action: String,
+    pub deploy_id: Option<String>,
+    /// The raw body, only kept with `DEBUG_STORE_BODIES`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+/// The most recent webhook deliveries, kept in memory only
+pub struct DeliveryLog {
+    capacity: usize,
+    entries: StdMutex<VecDeque<DeliveryRecord>>,
+}
+
+impl DeliveryLog {
+    pub fn new(capacity: usize) -> Self {
+        DeliveryLog {
+            capacity,
+            entries: StdMutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, delivery: DeliveryRecord) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(delivery);
+    }
+
+    /// The most recent deliveries, newest first
+    pub fn recent(&self, limit: usize) -> Vec<DeliveryRecord> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+/// Spots containers that keep getting restarted by their restart policy
+pub struct CrashLoopDetector {
+    max_starts: usize,
+    window: Duration,
+    // Start times of each container, keyed by ID so a redeploy starts over
+    starts: HashMap<String, VecDeque<Instant>>,
+}
+
+impl CrashLoopDetector {
+    pub fn new(max_starts: usize, window: Duration) -> Self {
+        CrashLoopDetector {
+            max_starts,
+            window,
+            starts: HashMap::new(),
+        }
+    }
+
+    /// Records a start of container `id`, returning whether it was started
+    /// more than `max_starts` times within the window
+    pub fn record(&mut self, id: &str, at: Instant) -> bool {
+        let window = self.window;
+        let recent = |start: &Instant| at.saturating_duration_since(*start) <= window;
+        // Containers that stopped restarting are forgotten
+        self.starts
+            .retain(|_, starts| starts.back().is_some_and(&recent));
+
+        let starts = self.starts.entry(id.to_string()).or_default();
+        starts.retain(|start| recent(start));
+        starts.push_back(at);
+        if starts.len() > self.max_starts {
+            self.starts.remove(id);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Journal of pending deploys, persisted so they survive restarts
+pub struct Journal {
+    path: PathBuf,
+    entries: Mutex<Vec<PendingDeploy>>,
+}
+
+impl Journal {
+    pub fn new<P: AsRef<Path>>(state_dir: P) -> Self {
+        Journal {
+            path: state_dir.as_ref().join("pending.json"),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Loads the entries left over from a previous run
+    pub async fn load(&self) -> Result<Vec<PendingDeploy>> {
+        let mut entries = self.entries.lock().await;
+        if self.path.is_file() {
+            let data = fs::read_to_string(&self.path)
+                .await
+                .context(format!("unable to read journal {:#?}", self.path))?;
+            *entries = serde_json::from_str(&data)
+                .context(format!("unable to parse journal {:#?}", self.path))?;
+        }
+
+        Ok(entries.clone())
+    }
+
+    pub async fn record(&self, entry: PendingDeploy) -> Result<()> {
+        let mut entries = self.entries.lock().await;
+        entries.push(entry);
+        self.persist(&entries).await
+    }
+
+    pub async fn complete(&self, entry: &PendingDeploy) -> Result<()> {
+        let mut entries = self.entries.lock().await;
+        if let Some(idx) = entries.iter().position(|e| e == entry) {
+            entries.remove(idx);
+        }
+        self.persist(&entries).await
+    }
+
+    async fn persist(&self, entries: &[PendingDeploy]) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .context(format!("unable to create state directory {:#?}", dir))?;
+        }
+
+        // Write to a temporary file first so a crash never leaves a truncated journal
+        let tmp_path = self.path.with_extension("json.tmp");
+        // Serializing plain strings can't fail, we can unwrap safely
+        fs::write(&tmp_path, serde_json::to_vec(entries).unwrap())
+            .await
+            .context(format!("unable to write journal {:#?}", tmp_path))?;
+        fs::rename(&tmp_path, &self.path)
+            .await
+            .context(format!("unable to replace journal {:#?}", self.path))
+    }
+}
+
+/// A finished deploy, as recorded in the history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub name: String,
+    pub commit: Option<String>,
+    pub delivery: Option<String>,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+    #[serde(flatten)]
+    pub state: DeployState,
+}
+
+/// Bounded log of finished deploys, stored as JSON lines.
+///
+/// Entries are appended as they come in, and the file is cut down to the
+/// most recent `capacity` entries once it grows to twice that.
+pub struct History {
+    path: PathBuf,
+    capacity: usize,
+    // Lines in the file, counted on first use
+    len: Mutex<Option<usize>>,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(state_dir: P, capacity: usize) -> Self {
+        History {
+            path: state_dir.as_ref().join("history.jsonl"),
+            capacity,
+            len: Mutex::new(None),
+        }
+    }
+
+    pub async fn append(&self, entry: &HistoryEntry) -> Result<()> {
+        let mut len = self.len.lock().await;
+        let mut count = match *len {
+            Some(count) => count,
+            None => self.read().await?.len(),
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .context(format!("unable to create state directory {:#?}", dir))?;
+        }
+        // Serializing plain strings and numbers can't fail, we can unwrap safely
+        let mut line = serde_json::to_vec(entry).unwrap();
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .context(format!("unable to open history {:#?}", self.path))?;
+        // Flushing waits for the write to actually happen, it is done in the background otherwise
+        file.write_all(&line)
+            .await
+            .and(file.flush().await)
+            .context(format!("unable to write history {:#?}", self.path))?;
+        count += 1;
+
+        if count >= self.capacity * 2 {
+            let entries = self.read().await?;
+            let keep = &entries[entries.len().saturating_sub(self.capacity)..];
+            let mut data = Vec::new();
+            for entry in keep {
+                data.extend(serde_json::to_vec(entry).unwrap());
+                data.push(b'\n');
+            }
+            // Write to a temporary file first so a crash never leaves a truncated history
+            let tmp_path = self.path.with_extension("jsonl.tmp");
+            fs::write(&tmp_path, data)
+                .await
+                .context(format!("unable to write history {:#?}", tmp_path))?;
+            fs::rename(&tmp_path, &self.path)
+                .await
+                .context(format!("unable to replace history {:#?}", self.path))?;
+            count = keep.len();
+        }
+        *len = Some(count);
+
+        Ok(())
+    }
+
+    /// The most recent entries, newest first, optionally only those of `name`
+    pub async fn query(&self, name: Option<&str>, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let _len = self.len.lock().await;
+        Ok(self
+            .read()
+            .await?
+            .into_iter()
+            .rev()
+            .filter(|entry| name.is_none_or(|name| entry.name == name))
+            .take(limit)
+            .collect())
+    }
+
+    async fn read(&self) -> Result<Vec<HistoryEntry>> {
+        if !self.path.is_file() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&self.path)
+            .await
+            .context(format!("unable to read history {:#?}", self.path))?;
+        // A line cut short by a crash only loses that entry
+        Ok(data
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hermes-test-{}-{}", name, std::process::id()))
+    }
+
+    fn entry(id: usize) -> HistoryEntry {
+        HistoryEntry {
+            id: id.to_string(),
+            name: if id.is_multiple_of(2) { "even" } else { "odd" }.to_string(),
+            commit: None,
+            delivery: None,
+            started_at: id as u64,
+            finished_at: id as u64,
+            state: DeployState::Succeeded,
+        }
+    }
+
+    #[tokio::test]
+    async fn history_is_compacted_to_its_capacity() {
+        let dir = temp_dir("history");
+        let history = History::new(&dir, 3);
+        for id in 0..5 {
+            history.append(&entry(id)).await.unwrap();
+        }
+        // Below twice the capacity nothing is dropped
+        assert_eq!(history.read().await.unwrap().len(), 5);
+
+        history.append(&entry(5)).await.unwrap();
+        let ids: Vec<_> = history
+            .read()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, ["3", "4", "5"]);
+
+        let newest = history.query(Some("odd"), 10).await.unwrap();
+        let ids: Vec<_> = newest.into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["5", "3"]);
+        let newest = history.query(None, 2).await.unwrap();
+        let ids: Vec<_> = newest.into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["5", "4"]);
+
+        // A fresh instance counts the lines already in the file
+        let history = History::new(&dir, 3);
+        for id in 6..9 {
+            history.append(&entry(id)).await.unwrap();
+        }
+        let ids: Vec<_> = history
+            .read()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(ids, ["6", "7", "8"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn journal_entries_survive_a_restart_until_completed() {
+        let dir = temp_dir("journal");
+        let journal = Journal::new(&dir);
+        assert!(journal.load().await.unwrap().is_empty());
+
+        let deploy = |commit: &str| {
+            PendingDeploy::new(
+                "app".to_string(),
+                "git@github.com:o/app.git".to_string(),
+                Some(commit.to_string()),
+                None,
+                None,
+            )
+        };
+        let (first, second) = (deploy("abc"), deploy("def"));
+        journal.record(first.clone()).await.unwrap();
+        journal.record(second.clone()).await.unwrap();
+        journal.complete(&first).await.unwrap();
+
+        let reloaded = Journal::new(&dir).load().await.unwrap();
+        assert_eq!(reloaded, [second]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn journal_entries_without_an_id_get_one() {
+        let entries: Vec<PendingDeploy> =
+            serde_json::from_str(r#"[{"name": "app", "repo_url": "/dev/null", "commit": null}]"#)
+                .unwrap();
+        assert!(Uuid::parse_str(&entries[0].id).is_ok());
+    }
+
+    #[test]
+    fn statuses_are_kept_for_the_most_recent_deploys() {
+        let statuses = StatusMap::new(2);
+        let deploys: Vec<_> = (0..3)
+            .map(|_| {
+                PendingDeploy::new("app".to_string(), "/dev/null".to_string(), None, None, None)
+            })
+            .collect();
+        for deploy in &deploys {
+            statuses.insert(deploy, DeployState::Queued);
+        }
+        statuses.set_state(&deploys[2].id, DeployState::Succeeded);
+
+        assert!(statuses.get(&deploys[0].id).is_none());
+        assert!(matches!(
+            statuses.get(&deploys[1].id).unwrap().state,
+            DeployState::Queued
+        ));
+        assert!(matches!(
+            statuses.get(&deploys[2].id).unwrap().state,
+            DeployState::Succeeded
+        ));
+    }
+
+    #[test]
+    fn deliveries_are_listed_newest_first_up_to_the_capacity() {
+        let log = DeliveryLog::new(2);
+        for status in [200, 202, 401] {
+            log.record(DeliveryRecord {
+                received_at: 0,
+                provider: None,
+                event: None,
+                id: None,
+                name: None,
+                signature_valid: None,
+                status,
+                action: String::new(),
+                deploy_id: None,
+                body: (status == 401).then(|| "{}".to_string()),
+            });
+        }
+        let statuses: Vec<_> = log.recent(10).iter().map(|d| d.status).collect();
+        assert_eq!(statuses, [401, 202]);
+        assert_eq!(log.recent(1).len(), 1);
+
+        // Bodies are left out unless they were stored
+        let listed = serde_json::to_value(log.recent(10)).unwrap();
+        assert_eq!(listed[0]["body"], "{}");
+        assert!(listed[1].get("body").is_none());
+    }
+
+    #[test]
+    fn rapid_restarts_are_crash_loops() {
+        let mut detector = CrashLoopDetector::new(3, Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        for secs in [0, 10, 20] {
+            assert!(!detector.record("0123abcd", at(secs)));
+        }
+        // Other containers don't count
+        assert!(!detector.record("4567cdef", at(25)));
+        assert!(detector.record("0123abcd", at(30)));
+
+        // Once reported, a container starts over, and old starts leave the window
+        for secs in [31, 40, 50, 100] {
+            assert!(!detector.record("0123abcd", at(secs)));
+        }
+        assert!(!detector.record("4567cdef", at(200)));
+    }
+}
@@ -1,17 +1,167 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    env::{self, VarError},
+    fs,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Checks that `name` is safe to use as a single path component.
+///
+/// Only ASCII alphanumerics, `_`, `.` and `-` are allowed, the first character
+/// must be alphanumeric, and `..` is rejected anywhere in the name.
+pub fn sanitize_name(name: &str) -> Result<&str> {
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        bail!("name {:?} must start with a letter or digit", name);
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"_.-".contains(*c))
+    {
+        bail!("name {:?} contains invalid character {:?}", name, c);
+    }
+    if name.contains("..") {
+        bail!("name {:?} must not contain \"..\"", name);
+    }
+
+    Ok(name)
+}
+
+/// Reads `key` from the environment, or from the file at `<key>_FILE`, which
+/// takes precedence
+pub fn env_or_file(key: &str) -> Result<Option<String>> {
+    let file_key = [key, "_FILE"].concat();
+    match env::var(&file_key) {
+        Ok(path) => {
+            let contents = fs::read_to_string(&path)
+                .context(format!("unable to read {} from {:#?}", file_key, path))?;
+            Ok(Some(contents.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        Err(_) => Ok(env::var(key).ok()),
+    }
+}
+
+/// Parses `key` from the environment, or `None` if it isn't set. A value that
+/// doesn't parse is an error instead of falling back to a default.
+pub fn env_parse<T: FromStr>(key: &str) -> Result<Option<T>> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("invalid {} value '{}'", key, value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(value)) => bail!("invalid {} value {:?}", key, value),
+    }
+}
+
+/// `env_parse` for settings that were checked at startup already
+pub fn env_setting<T: FromStr>(key: &str) -> Option<T> {
+    env_parse(key).unwrap_or_else(|why| panic!("{:#}", why))
+}
+
+/// Keeps the last `max` bytes of some output, where errors usually are
+pub struct LogTail {
+    max: usize,
+    buf: Vec<u8>,
+    dropped: usize,
+}
+
+impl LogTail {
+    pub fn new(max: usize) -> Self {
+        LogTail {
+            max,
+            buf: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        // Dropping in bulk keeps pushing lots of small chunks cheap
+        if self.buf.len() > self.max * 2 {
+            self.trim();
+        }
+    }
+
+    /// The kept output, prefixed with a marker if anything was dropped
+    pub fn finish(mut self) -> String {
+        self.trim();
+        let tail = String::from_utf8_lossy(&self.buf);
+        if self.dropped > 0 {
+            format!("[{} bytes truncated]\n{}", self.dropped, tail)
+        } else {
+            tail.into_owned()
+        }
+    }
+
+    fn trim(&mut self) {
+        let excess = self.buf.len().saturating_sub(self.max);
+        self.buf.drain(..excess);
+        self.dropped += excess;
+    }
+}
+
+/// Parses a duration like `90`, `30s`, `15m`, `168h` or `7d`, in seconds
+/// without a unit
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let secs = digits.parse::<u64>().ok()?.checked_mul(multiplier)?;
+    Some(Duration::from_secs(secs))
+}
+
+/// Reads a boolean flag from the environment, accepting `1` or `true`
+pub fn env_flag(key: &str) -> bool {
+    env::var(key)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
 pub mod git {
-    use anyhow::{Context, Result};
+    use crate::{config::redacted_url, DRY_RUN, GIT_TIMEOUT};
+    use anyhow::{anyhow, bail, Context, Result};
     use git2::{
         build::{CheckoutBuilder, RepoBuilder},
-        Cred, FetchOptions, RebaseOptions, RemoteCallbacks, Repository,
+        Cred, ErrorCode, FetchOptions, RebaseOptions, Remote, RemoteCallbacks, Repository,
+        ResetType,
+    };
+    use globset::Glob;
+    use semver::Version;
+    use std::{
+        path::{Path, PathBuf},
+        sync::atomic::Ordering,
+        time::{Duration, Instant},
     };
-    use std::path::{Path, PathBuf};
 
+    #[derive(Clone)]
     pub struct KeyPair {
         pub public: PathBuf,
         pub private: PathBuf,
     }
 
-    fn fetch_options(ssh_key: &KeyPair) -> FetchOptions {
+    impl KeyPair {
+        /// The key at `path`, with its public half next to it under a `.pub` extension
+        pub fn from_path(path: &Path) -> Self {
+            let private = path.to_path_buf();
+            let public = private.with_extension("pub");
+
+            KeyPair { public, private }
+        }
+    }
+
+    /// The fetch depth that turns a shallow clone into a full one
+    const UNSHALLOW: i32 = i32::MAX;
+
+    fn fetch_options(ssh_key: &KeyPair, depth: Option<i32>, timeout: Duration) -> FetchOptions<'_> {
         let mut callbacks = RemoteCallbacks::new();
         callbacks.credentials(move |_url, username_from_url, _allowed_types| {
             Cred::ssh_key(
@@ -21,39 +171,124 @@ pub mod git {
                 None,
             )
         });
+        // Returning false from a progress callback aborts the transfer, which
+        // frees the blocking thread once a slow remote sends anything
+        let deadline = Instant::now() + timeout;
+        callbacks.transfer_progress(move |_| Instant::now() < deadline);
+        callbacks.sideband_progress(move |_| Instant::now() < deadline);
         let mut fo = FetchOptions::new();
         fo.remote_callbacks(callbacks);
+        if let Some(depth) = depth {
+            fo.depth(depth);
+        }
         fo
     }
 
-    pub fn clone(ssh_key: &KeyPair, url: &str, path: &Path) -> Result<bool> {
+    /// The depth to fetch a repo with, bringing back the full history of a
+    /// repo that was shallow before `depth` was unset
+    fn fetch_depth(repo: &Repository, depth: Option<u32>) -> Option<i32> {
+        match depth {
+            Some(depth) => Some(depth.min(UNSHALLOW as u32) as i32),
+            None if repo.is_shallow() => Some(UNSHALLOW),
+            None => None,
+        }
+    }
+
+    /// Clones `url` into `path`, checking out `branch` or the remote's default,
+    /// with only the last `depth` commits if set
+    pub fn clone(
+        ssh_key: &KeyPair,
+        remote: &str,
+        url: &str,
+        path: &Path,
+        branch: Option<&str>,
+        depth: Option<u32>,
+    ) -> Result<bool> {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!(
+                "[dry run] Would clone {} into {:#?}",
+                redacted_url(url),
+                path
+            );
+            return Ok(false);
+        }
         let mut builder = RepoBuilder::new();
-        builder.fetch_options(fetch_options(ssh_key));
+        builder.fetch_options(fetch_options(
+            ssh_key,
+            depth.map(|depth| depth.min(UNSHALLOW as u32) as i32),
+            *GIT_TIMEOUT,
+        ));
+        builder.remote_create(|repo, _name, url| repo.remote(remote, url));
+        if let Some(branch) = branch {
+            builder.branch(branch);
+        }
 
-        builder
-            .clone(url, path)
-            .context(format!("unable to clone {}", url))?;
+        if let Err(why) = builder.clone(url, path) {
+            if let (Some(branch), ErrorCode::NotFound) = (branch, why.code()) {
+                bail!("branch '{}' not found on remote", branch);
+            }
+            return Err(why).context(format!("unable to clone {}", url));
+        }
 
         Ok(true)
     }
 
-    pub fn fetch(ssh_key: &KeyPair, url: &str, path: &Path) -> Result<bool> {
+    fn find_remote<'r>(repo: &'r Repository, remote: &str, path: &Path) -> Result<Remote<'r>> {
+        repo.find_remote(remote).map_err(|_| {
+            let available = repo
+                .remotes()
+                .map(|names| names.iter().flatten().collect::<Vec<_>>().join(", "))
+                .unwrap_or_default();
+            anyhow!(
+                "remote {:?} not found in {:#?}, available remotes: [{}]",
+                remote,
+                path,
+                available
+            )
+        })
+    }
+
+    pub fn fetch(
+        ssh_key: &KeyPair,
+        remote: &str,
+        url: &str,
+        path: &Path,
+        branch: &str,
+        depth: Option<u32>,
+    ) -> Result<bool> {
         let repo = Repository::open(path)?;
-        let mut remote = repo.find_remote("origin")?;
+        let mut remote = find_remote(&repo, remote, path)?;
+        let refname = ["refs/heads/", branch].concat();
+        let mut fo = fetch_options(ssh_key, fetch_depth(&repo, depth), *GIT_TIMEOUT);
         remote
-            .fetch(&["main"], Some(&mut fetch_options(ssh_key)), None)
+            .fetch(&[&refname], Some(&mut fo), None)
             .context(format!("unable to fetch {}", url))?;
-        // Since we just fetched, we are guaranteed to have a FETCH_HEAD, so we can unwrap safely
-        let fetchhead = repo
-            .annotated_commit_from_fetchhead(
-                "main",
-                url,
-                &repo.refname_to_id("FETCH_HEAD").unwrap(),
-            )
-            .unwrap();
+        // Fetching a ref the remote doesn't have succeeds without fetching anything,
+        // so the advertised refs, which outlive the connection, are checked instead
+        let oid = remote
+            .list()?
+            .iter()
+            .find(|head| head.name() == refname)
+            .map(|head| head.oid())
+            .ok_or_else(|| anyhow!("branch '{}' not found on remote", branch))?;
+        let fetchhead = repo.annotated_commit_from_fetchhead(branch, url, &oid)?;
+
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!("[dry run] Would rebase {:#?} onto {}", path, fetchhead.id());
+            return Ok(false);
+        }
 
+        let head = repo.head()?.target();
         let mut cb = CheckoutBuilder::new();
         cb.force();
+        // A shallow history may not reach back to where the branch forked, so
+        // the branch is moved to what was fetched instead of rebased onto it
+        if repo.is_shallow() {
+            let commit = repo.find_commit(fetchhead.id())?;
+            repo.reset(commit.as_object(), ResetType::Hard, Some(&mut cb))
+                .context(format!("unable to reset {:#?} to {}", path, commit.id()))?;
+            return Ok(head != Some(commit.id()));
+        }
         let mut ro = RebaseOptions::new();
         ro.checkout_options(cb);
 
@@ -63,73 +298,1004 @@ pub mod git {
         rebase
             .finish(None)
             .context(format!("unable to finish rebase on {:#?}", path))?;
-        if rebase.len() == 0 {
-            Ok(false)
-        } else {
-            for _ in rebase {}
-            Ok(true)
+
+        let moved = repo.head()?.target() != head;
+        Ok(moved)
+    }
+
+    /// Picks the highest semver tag matching `pattern`, or the newest one if
+    /// none of them are semver
+    fn latest_tag(repo: &Repository, pattern: Option<&str>) -> Result<String> {
+        let matcher = pattern
+            .map(|p| Glob::new(p).map(|glob| glob.compile_matcher()))
+            .transpose()
+            .context("invalid tag pattern")?;
+        let names = repo.tag_names(None)?;
+        let tags: Vec<_> = names
+            .iter()
+            .flatten()
+            .filter(|tag| matcher.as_ref().map(|m| m.is_match(tag)).unwrap_or(true))
+            .collect();
+
+        let highest = tags
+            .iter()
+            .filter_map(|tag| {
+                let version = Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()?;
+                Some((version, *tag))
+            })
+            .max();
+        if let Some((_, tag)) = highest {
+            return Ok(tag.to_string());
+        }
+
+        tags.iter()
+            .filter_map(|tag| {
+                let commit = repo
+                    .revparse_single(&["refs/tags/", tag].concat())
+                    .ok()?
+                    .peel_to_commit()
+                    .ok()?;
+                Some((commit.time().seconds(), *tag))
+            })
+            .max()
+            .map(|(_, tag)| tag.to_string())
+            .ok_or_else(|| anyhow!("no tags matching {:?}", pattern.unwrap_or("*")))
+    }
+
+    /// Fetches the tags of a repo and checks out `tag`, or the latest one
+    /// matching `pattern` if no tag is given
+    pub fn checkout_tag(
+        ssh_key: &KeyPair,
+        remote: &str,
+        url: &str,
+        path: &Path,
+        pattern: Option<&str>,
+        tag: Option<&str>,
+        depth: Option<u32>,
+    ) -> Result<bool> {
+        let cloned = !path.is_dir() && clone(ssh_key, remote, url, path, None, depth)?;
+        // Dry runs don't clone, so a new repo has nothing to check out
+        if !path.is_dir() && DRY_RUN.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let repo = Repository::open(path)?;
+        find_remote(&repo, remote, path)?
+            .fetch(
+                &["+refs/tags/*:refs/tags/*"],
+                Some(&mut fetch_options(
+                    ssh_key,
+                    fetch_depth(&repo, depth),
+                    *GIT_TIMEOUT,
+                )),
+                None,
+            )
+            .context(format!("unable to fetch tags of {}", url))?;
+
+        let tag = match tag {
+            Some(tag) => tag.to_string(),
+            None => latest_tag(&repo, pattern)?,
+        };
+        let commit = repo
+            .revparse_single(&["refs/tags/", &tag].concat())
+            .and_then(|obj| obj.peel_to_commit())
+            .context(format!("tag {:?} not found in {}", tag, url))?;
+        let head = repo.head().ok().and_then(|head| head.target());
+
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!("[dry run] Would check out tag {} in {:#?}", tag, path);
+            return Ok(false);
         }
+
+        let mut cb = CheckoutBuilder::new();
+        cb.force();
+        repo.checkout_tree(commit.as_object(), Some(&mut cb))
+            .context(format!("unable to check out tag {} in {:#?}", tag, path))?;
+        repo.set_head_detached(commit.id())
+            .context(format!("unable to check out tag {} in {:#?}", tag, path))?;
+        info!("Checked out tag {} in {:#?}", tag, path);
+
+        Ok(cloned || head != Some(commit.id()))
+    }
+
+    /// The commit checked out in the repo at `path`, if there is one
+    pub fn head_commit(path: &Path) -> Option<String> {
+        let repo = Repository::open(path).ok()?;
+        let commit = repo.head().ok()?.target()?;
+        Some(commit.to_string())
+    }
+
+    /// Checks `commit`, or the one at HEAD if unset, of the repo at `path` out
+    /// into `target`, leaving the repo's own working tree and index alone.
+    /// Returns the ID of the commit.
+    pub fn export_commit(path: &Path, target: &Path, commit: Option<&str>) -> Result<String> {
+        let repo = Repository::open(path)?;
+        let commit = match commit {
+            Some(commit) => repo
+                .revparse_single(commit)
+                .and_then(|object| object.peel_to_commit())
+                .context(format!("unable to find commit {} in {:#?}", commit, path))?,
+            None => repo
+                .head()
+                .and_then(|head| head.peel_to_commit())
+                .context(format!("no commit checked out in {:#?}", path))?,
+        };
+        // libgit2 resolves a relative target against the repo's working tree
+        let target = std::fs::create_dir_all(target)
+            .and_then(|_| target.canonicalize())
+            .context(format!("unable to create worktree {:#?}", target))?;
+        let mut cb = CheckoutBuilder::new();
+        cb.target_dir(&target)
+            .force()
+            .recreate_missing(true)
+            .update_index(false);
+        repo.checkout_tree(commit.as_object(), Some(&mut cb))
+            .context(format!(
+                "unable to check out {} into {:#?}",
+                commit.id(),
+                target
+            ))?;
+
+        Ok(commit.id().to_string())
     }
 
-    pub fn clone_or_fetch_repo(ssh_key: &KeyPair, url: &str, path: &Path) -> Result<bool> {
-        if path.is_dir() {
-            fetch(ssh_key, url, path)
+    /// Clones or fetches `branch`, with only the last `depth` commits if set.
+    /// A shallow clone that doesn't reach back to `commit` gets its full history.
+    pub fn clone_or_fetch_repo(
+        ssh_key: &KeyPair,
+        remote: &str,
+        url: &str,
+        path: &Path,
+        branch: &str,
+        depth: Option<u32>,
+        commit: Option<&str>,
+    ) -> Result<bool> {
+        let changed = if path.is_dir() {
+            fetch(ssh_key, remote, url, path, branch, depth)?
         } else {
-            clone(ssh_key, url, path)
+            clone(ssh_key, remote, url, path, Some(branch), depth)?
+        };
+        // Dry runs don't clone, so a new repo has no history to complete
+        if !path.is_dir() && DRY_RUN.load(Ordering::Relaxed) {
+            return Ok(changed);
+        }
+
+        let repo = Repository::open(path)?;
+        if let Some(commit) = commit.filter(|_| repo.is_shallow()) {
+            if repo.revparse_single(commit).is_err() {
+                info!("Fetching the full history of {:#?} for {}", path, commit);
+                find_remote(&repo, remote, path)?
+                    .fetch(
+                        &[&["refs/heads/", branch].concat()],
+                        Some(&mut fetch_options(ssh_key, Some(UNSHALLOW), *GIT_TIMEOUT)),
+                        None,
+                    )
+                    .context(format!("unable to fetch the history of {}", url))?;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use git2::Signature;
+
+        /// Commits `contents` as the file `app.txt`, returning the commit ID
+        fn commit_file(repo: &Repository, contents: &str) -> String {
+            let workdir = repo.workdir().unwrap();
+            std::fs::write(workdir.join("app.txt"), contents).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("app.txt")).unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = Signature::now("hermes", "hermes@example.com").unwrap();
+            let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+            let parents: Vec<_> = parent.iter().collect();
+            repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                contents,
+                &tree,
+                &parents,
+            )
+            .unwrap()
+            .to_string()
+        }
+
+        /// A repository at `path` whose `main` branch has a single commit
+        fn upstream(path: &Path) -> Repository {
+            let repo = Repository::init(path).unwrap();
+            repo.set_head("refs/heads/main").unwrap();
+            commit_file(&repo, "first");
+            repo
+        }
+
+        #[test]
+        fn the_configured_remote_is_used() {
+            let dir =
+                std::env::temp_dir().join(format!("hermes-test-remote-{}", std::process::id()));
+            let url = dir.join("upstream").to_str().unwrap().to_string();
+            let key = KeyPair {
+                public: dir.join("id_ed25519.pub"),
+                private: dir.join("id_ed25519"),
+            };
+            let upstream = upstream(&dir.join("upstream"));
+
+            let path = dir.join("repo");
+            assert!(clone(&key, "upstream", &url, &path, None, None).unwrap());
+            let repo = Repository::open(&path).unwrap();
+            assert!(repo.find_remote("upstream").is_ok());
+            assert!(repo.find_remote("origin").is_err());
+
+            commit_file(&upstream, "second");
+            fetch(&key, "upstream", &url, &path, "main", None).unwrap();
+            let contents = std::fs::read_to_string(path.join("app.txt")).unwrap();
+            assert_eq!(contents, "second");
+
+            let why = fetch(&key, "origin", &url, &path, "main", None).unwrap_err();
+            assert!(
+                why.to_string().contains("available remotes: [upstream]"),
+                "{}",
+                why
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn fetches_report_whether_head_moved() {
+            let dir = std::env::temp_dir().join(format!("hermes-test-poll-{}", std::process::id()));
+            let url = dir.join("upstream").to_str().unwrap().to_string();
+            let key = KeyPair {
+                public: dir.join("id_ed25519.pub"),
+                private: dir.join("id_ed25519"),
+            };
+            let upstream = upstream(&dir.join("upstream"));
+            let path = dir.join("repo");
+            assert!(clone_or_fetch_repo(&key, "origin", &url, &path, "main", None, None).unwrap());
+
+            assert!(!clone_or_fetch_repo(&key, "origin", &url, &path, "main", None, None).unwrap());
+            let second = commit_file(&upstream, "second");
+            assert!(clone_or_fetch_repo(&key, "origin", &url, &path, "main", None, None).unwrap());
+            let head = Repository::open(&path).unwrap().head().unwrap().target();
+            assert_eq!(head.unwrap().to_string(), second);
+            assert!(!clone_or_fetch_repo(&key, "origin", &url, &path, "main", None, None).unwrap());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn missing_branches_are_reported_clearly() {
+            let dir =
+                std::env::temp_dir().join(format!("hermes-test-branch-{}", std::process::id()));
+            let url = dir.join("upstream").to_str().unwrap().to_string();
+            let key = KeyPair {
+                public: dir.join("id_ed25519.pub"),
+                private: dir.join("id_ed25519"),
+            };
+            upstream(&dir.join("upstream"));
+
+            let path = dir.join("repo");
+            let why = clone_or_fetch_repo(&key, "origin", &url, &path, "release", None, None)
+                .unwrap_err();
+            assert_eq!(why.to_string(), "branch 'release' not found on remote");
+            assert!(clone_or_fetch_repo(&key, "origin", &url, &path, "main", None, None).unwrap());
+            let why = clone_or_fetch_repo(&key, "origin", &url, &path, "release", None, None)
+                .unwrap_err();
+            assert_eq!(why.to_string(), "branch 'release' not found on remote");
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        /// Serves a repository over smart HTTP that advertises a branch, but
+        /// then only trickles progress messages instead of sending a pack,
+        /// ending the response after `lasts`
+        fn slow_remote(lasts: std::time::Duration) -> String {
+            use futures::stream;
+            use hyper::{
+                service::{make_service_fn, service_fn},
+                Body, Request, Response, Server,
+            };
+            use std::convert::Infallible;
+
+            fn pkt(data: &[u8]) -> Vec<u8> {
+                [format!("{:04x}", data.len() + 4).as_bytes(), data].concat()
+            }
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let url = format!("http://{}/app.git", listener.local_addr().unwrap());
+            std::thread::spawn(move || {
+                let service = make_service_fn(move |_| async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+                        let commit = "0123456789abcdef0123456789abcdef01234567";
+                        if req.uri().path().ends_with("/info/refs") {
+                            let refs = [
+                                pkt(b"# service=git-upload-pack\n"),
+                                b"0000".to_vec(),
+                                pkt(
+                                    format!("{} HEAD\0side-band-64k multi_ack_detailed\n", commit)
+                                        .as_bytes(),
+                                ),
+                                pkt(format!("{} refs/heads/main\n", commit).as_bytes()),
+                                b"0000".to_vec(),
+                            ];
+                            let res = Response::builder()
+                                .header(
+                                    "Content-Type",
+                                    "application/x-git-upload-pack-advertisement",
+                                )
+                                .body(Body::from(refs.concat()));
+                            return Ok::<_, Infallible>(res.unwrap());
+                        }
+
+                        let start = tokio::time::Instant::now();
+                        let progress = stream::unfold(true, move |first| async move {
+                            if first {
+                                return Some((Ok::<_, Infallible>(pkt(b"NAK\n")), false));
+                            }
+                            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                            (start.elapsed() < lasts)
+                                .then(|| (Ok(pkt(b"\x02Counting objects\n")), false))
+                        });
+                        let res = Response::builder()
+                            .header("Content-Type", "application/x-git-upload-pack-result")
+                            .body(Body::wrap_stream(progress));
+                        Ok(res.unwrap())
+                    }))
+                });
+                tokio::runtime::Runtime::new()
+                    .unwrap()
+                    .block_on(async move {
+                        let server = Server::from_tcp(listener).unwrap();
+                        server.serve(service).await.unwrap();
+                    })
+            });
+            url
+        }
+
+        #[test]
+        fn fetches_from_a_slow_remote_stop_at_the_deadline() {
+            let dir = std::env::temp_dir()
+                .join(["hermes-test-", &uuid::Uuid::new_v4().to_string()].concat());
+            let repo = Repository::init(&dir).unwrap();
+            let url = slow_remote(std::time::Duration::from_secs(30));
+            let key = KeyPair::from_path(&dir.join("key"));
+
+            let start = Instant::now();
+            let timeout = Duration::from_millis(200);
+            let mut remote = repo.remote_anonymous(&url).unwrap();
+            let mut fo = fetch_options(&key, None, timeout);
+            let fetched = remote.fetch(&["refs/heads/main"], Some(&mut fo), None);
+            assert!(fetched.is_err());
+            assert!(start.elapsed() >= timeout);
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{:?}",
+                start.elapsed()
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn tags_are_picked_by_semver_then_age() {
+            let dir = std::env::temp_dir().join(format!("hermes-test-tags-{}", std::process::id()));
+            let url = dir.join("upstream").to_str().unwrap().to_string();
+            let key = KeyPair {
+                public: dir.join("id_ed25519.pub"),
+                private: dir.join("id_ed25519"),
+            };
+            let upstream = upstream(&dir.join("upstream"));
+            let tag = |name: &str, contents: &str| {
+                let commit = commit_file(&upstream, contents);
+                let commit = upstream.revparse_single(&commit).unwrap();
+                upstream.tag_lightweight(name, &commit, false).unwrap();
+            };
+            tag("v1.9.0", "1.9");
+            tag("v1.10.0", "1.10");
+            tag("v2.0.0", "2.0");
+            // Commit times only have a precision of seconds, and the newest
+            // tag sorts first so it can't win by name
+            tag("release-b", "release b");
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            tag("release-a", "release a");
+
+            let path = dir.join("repo");
+            let checked_out = || std::fs::read_to_string(path.join("app.txt")).unwrap();
+            assert!(checkout_tag(&key, "origin", &url, &path, Some("v1.*"), None, None).unwrap());
+            assert_eq!(checked_out(), "1.10");
+            assert!(!checkout_tag(&key, "origin", &url, &path, Some("v1.*"), None, None).unwrap());
+            assert!(checkout_tag(&key, "origin", &url, &path, None, None, None).unwrap());
+            assert_eq!(checked_out(), "2.0");
+            assert!(checkout_tag(&key, "origin", &url, &path, None, Some("v1.9.0"), None).unwrap());
+            assert_eq!(checked_out(), "1.9");
+            // Without any semver tags the newest one wins
+            assert!(
+                checkout_tag(&key, "origin", &url, &path, Some("release-*"), None, None).unwrap()
+            );
+            assert_eq!(checked_out(), "release a");
+
+            assert!(checkout_tag(&key, "origin", &url, &path, Some("v3.*"), None, None).is_err());
+            assert!(checkout_tag(&key, "origin", &url, &path, None, Some("v3.0.0"), None).is_err());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn shallow_repos_fetch_their_history_once_depth_is_unset() {
+            let dir = std::env::temp_dir()
+                .join(["hermes-test-", &uuid::Uuid::new_v4().to_string()].concat());
+            let repo = Repository::init(&dir).unwrap();
+            let head = commit_file(&repo, "first");
+            assert_eq!(fetch_depth(&repo, None), None);
+            assert_eq!(fetch_depth(&repo, Some(5)), Some(5));
+            assert_eq!(fetch_depth(&repo, Some(u32::MAX)), Some(UNSHALLOW));
+
+            std::fs::write(repo.path().join("shallow"), head).unwrap();
+            assert_eq!(fetch_depth(&repo, None), Some(UNSHALLOW));
+            assert_eq!(fetch_depth(&repo, Some(5)), Some(5));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // libgit2's local transport ignores the depth, so the clone is marked
+        // shallow by hand
+        #[test]
+        fn shallow_clones_move_to_the_fetched_commit() {
+            let dir = std::env::temp_dir()
+                .join(["hermes-test-", &uuid::Uuid::new_v4().to_string()].concat());
+            let upstream = Repository::init(dir.join("upstream")).unwrap();
+            let first = commit_file(&upstream, "first");
+            let branch = upstream.head().unwrap().shorthand().unwrap().to_string();
+            let url = dir.join("upstream").to_str().unwrap().to_string();
+            let (key, clone) = (KeyPair::from_path(&dir.join("key")), dir.join("clone"));
+
+            clone_or_fetch_repo(&key, "origin", &url, &clone, &branch, Some(1), None).unwrap();
+            let repo = Repository::open(&clone).unwrap();
+            std::fs::write(repo.path().join("shallow"), &first).unwrap();
+            assert!(repo.is_shallow());
+
+            let second = commit_file(&upstream, "second");
+            let changed =
+                clone_or_fetch_repo(&key, "origin", &url, &clone, &branch, Some(1), None).unwrap();
+            assert!(changed);
+            assert_eq!(repo.head().unwrap().target().unwrap().to_string(), second);
+            let contents = std::fs::read_to_string(clone.join("app.txt")).unwrap();
+            assert_eq!(contents, "second");
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn export_commit_writes_the_pinned_commit() {
+            let dir = std::env::temp_dir()
+                .join(["hermes-test-", &uuid::Uuid::new_v4().to_string()].concat());
+            let repo = Repository::init(dir.join("repo")).unwrap();
+            let first = commit_file(&repo, "first");
+            let second = commit_file(&repo, "second");
+
+            let head = export_commit(&dir.join("repo"), &dir.join("head"), None).unwrap();
+            assert_eq!(head, second);
+            let contents = std::fs::read_to_string(dir.join("head/app.txt")).unwrap();
+            assert_eq!(contents, "second");
+
+            let pinned =
+                export_commit(&dir.join("repo"), &dir.join("pinned"), Some(&first)).unwrap();
+            assert_eq!(pinned, first);
+            let contents = std::fs::read_to_string(dir.join("pinned/app.txt")).unwrap();
+            assert_eq!(contents, "first");
+
+            assert!(
+                export_commit(&dir.join("repo"), &dir.join("missing"), Some("0123abcd")).is_err()
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn overlapping_exports_each_get_their_own_commit() {
+            let dir = std::env::temp_dir()
+                .join(["hermes-test-", &uuid::Uuid::new_v4().to_string()].concat());
+            let repo = Repository::init(dir.join("repo")).unwrap();
+            let commits = ["first", "second", "third"].map(|contents| commit_file(&repo, contents));
+
+            let exports: Vec<_> = commits
+                .iter()
+                .enumerate()
+                .map(|(idx, commit)| {
+                    let (dir, commit) = (dir.clone(), commit.clone());
+                    std::thread::spawn(move || {
+                        let target = dir.join(idx.to_string());
+                        export_commit(&dir.join("repo"), &target, Some(&commit)).unwrap()
+                    })
+                })
+                .collect();
+            for (idx, export) in exports.into_iter().enumerate() {
+                assert_eq!(export.join().unwrap(), commits[idx]);
+                let target = dir.join(idx.to_string());
+                let contents = std::fs::read_to_string(target.join("app.txt")).unwrap();
+                assert_eq!(contents, ["first", "second", "third"][idx]);
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
         }
     }
 }
 
 pub mod docker {
-    use crate::config::Config;
-    use anyhow::{Context, Result};
+    use super::LogTail;
+    use crate::{
+        config::{redacted_env, Config, PullPolicy},
+        DRY_RUN, HEALTH_TIMEOUT, OUTPUT_TAIL_BYTES, START_ATTEMPTS, START_RETRY_DELAY,
+    };
+    use anyhow::{anyhow, bail, Context, Result};
     use bollard::{
         container::{
-            Config as ContainerConfig, CreateContainerOptions, ListContainersOptions,
-            StartContainerOptions,
+            Config as ContainerConfig, CreateContainerOptions, ListContainersOptions, LogOutput,
+            LogsOptions, NetworkingConfig, RemoveContainerOptions, RenameContainerOptions,
+            StartContainerOptions, StopContainerOptions, WaitContainerOptions,
         },
-        image::BuildImageOptions,
-        models::{ContainerSummaryInner, HostConfig},
+        errors::Error as DockerError,
+        image::{BuildImageOptions, ListImagesOptions, RemoveImageOptions, TagImageOptions},
+        models::{
+            BuildInfo, ContainerState, ContainerSummaryInner, EndpointIpamConfig, EndpointSettings,
+            HealthStatusEnum, HostConfig,
+        },
+        network::InspectNetworkOptions,
+        system::EventsOptions,
         Docker,
     };
-    use futures::stream::StreamExt;
-    use std::{collections::HashMap, path::Path};
-    use tar::Builder;
+    use futures::stream::{Stream, StreamExt};
+    use hmac_sha256::Hash;
+    use hyper::body::Bytes;
+    use ipnet::IpNet;
+    use std::{
+        cmp::Reverse,
+        collections::{HashMap, HashSet},
+        net::IpAddr,
+        path::Path,
+        sync::atomic::Ordering,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+    use tar::{Builder, Header};
+    use tokio::{process::Command, time};
+
+    /// Label set on every container created by hermes
+    pub const MANAGED_LABEL: &str = "hermes.managed";
+    /// Label holding the name of the config a container was created from
+    pub const NAME_LABEL: &str = "hermes.name";
+    /// Label holding a hash of the settings and image a container was created with
+    pub const CONFIG_HASH_LABEL: &str = "hermes.config-hash";
+
+    /// Path of the generated Dockerfile used to build a specific stage
+    const TARGET_DOCKERFILE: &str = ".hermes.Dockerfile";
+    const PING_ATTEMPTS: u32 = 4;
+    const PING_BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Cuts a Dockerfile right after the stage named `target`.
+    ///
+    /// The last stage is what gets built, so this has the same effect as
+    /// `docker build --target`, which bollard doesn't expose.
+    fn truncate_to_stage(dockerfile: &str, target: &str) -> Result<String> {
+        let is_from = |line: &str| {
+            line.trim_start()
+                .get(..5)
+                .map(|from| from.eq_ignore_ascii_case("FROM "))
+                .unwrap_or(false)
+        };
+        let lines: Vec<_> = dockerfile.lines().collect();
+        let start = lines
+            .iter()
+            .position(|line| {
+                let words: Vec<_> = line.split_whitespace().collect();
+                is_from(line)
+                    && words.len() >= 4
+                    && words[words.len() - 2].eq_ignore_ascii_case("AS")
+                    && words[words.len() - 1].eq_ignore_ascii_case(target)
+            })
+            .ok_or_else(|| anyhow!("build target {:?} not found in Dockerfile", target))?;
+        let end = lines[start + 1..]
+            .iter()
+            .position(|line| is_from(line))
+            .map(|i| start + 1 + i)
+            .unwrap_or(lines.len());
+
+        Ok(lines[..end].join("\n") + "\n")
+    }
+
+    /// The images the stages of a Dockerfile are built from, leaving out
+    /// `scratch`, earlier stages and images named by build arguments
+    fn base_images(dockerfile: &str) -> Vec<&str> {
+        let mut stages = Vec::new();
+        let mut images = Vec::new();
+        for line in dockerfile.lines() {
+            let mut words = line.split_whitespace();
+            if !words
+                .next()
+                .is_some_and(|word| word.eq_ignore_ascii_case("FROM"))
+            {
+                continue;
+            }
+            let mut words = words.skip_while(|word| word.starts_with("--"));
+            let image = match words.next() {
+                Some(image) => image,
+                None => continue,
+            };
+            if !image.eq_ignore_ascii_case("scratch")
+                && !image.contains('$')
+                && !stages
+                    .iter()
+                    .any(|stage: &&str| stage.eq_ignore_ascii_case(image))
+            {
+                images.push(image);
+            }
+            if words
+                .next()
+                .is_some_and(|word| word.eq_ignore_ascii_case("AS"))
+            {
+                stages.extend(words.next());
+            }
+        }
+
+        images
+    }
+
+    /// Checks that the daemon is reachable, backing off between attempts so a
+    /// restarting daemon has time to come back up
+    pub async fn ping(docker: &Docker) -> Result<()> {
+        ping_with_backoff(docker, PING_ATTEMPTS, PING_BACKOFF).await
+    }
+
+    async fn ping_with_backoff(docker: &Docker, attempts: u32, backoff: Duration) -> Result<()> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            match docker.ping().await {
+                Ok(_) => return Ok(()),
+                Err(why) if attempt == attempts => {
+                    return Err(anyhow!(why).context(format!(
+                        "Docker daemon unreachable after {} attempts",
+                        attempts
+                    )))
+                }
+                Err(why) => {
+                    warn!("Failed to reach Docker, retrying in {:?}: {}", delay, why);
+                    time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    pub async fn build_image(docker: &Docker, config: &Config, context_path: &Path) -> Result<()> {
+        let name = config.name.as_str();
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!(
+                "[dry run] Would build image {} from {:#?}",
+                name, context_path
+            );
+            return Ok(());
+        }
+        if let (Some(platforms), Some(registry)) = (&config.platforms, &config.registry) {
+            return build_multiplatform(docker, config, platforms, registry, context_path).await;
+        }
+        if let Some(url) = &config.build_context_url {
+            info!("Building {} from {}", name, url);
+            let stream = docker.build_image(
+                BuildImageOptions {
+                    t: name,
+                    remote: url.as_str(),
+                    labels: HashMap::from([(NAME_LABEL, name)]),
+                    pull: config.pull == PullPolicy::Always,
+                    q: false,
+                    ..Default::default()
+                },
+                None,
+                None,
+            );
+            return build_output(name, stream).await;
+        }
+
+        if config.pull == PullPolicy::Never {
+            let contents = std::fs::read_to_string(context_path.join("Dockerfile"))
+                .context(format!("unable to read Dockerfile in {:#?}", context_path))?;
+            for image in base_images(&contents) {
+                if docker.inspect_image(image).await.is_err() {
+                    bail!(
+                        "base image {} isn't available locally and pull is never",
+                        image
+                    );
+                }
+            }
+        }
 
-    pub async fn build_image(docker: &Docker, name: &str, repo_path: &Path) -> Result<()> {
         let mut tar_file = Builder::new(Vec::new());
-        tar_file.append_dir_all(".", repo_path).context(format!(
+        tar_file.append_dir_all(".", context_path).context(format!(
             "unable to append files in {:#?} to tar file",
-            repo_path
+            context_path
         ))?;
+        // An empty path builds the repo's own Dockerfile
+        let mut dockerfile = "";
+        if let Some(target) = &config.build_target {
+            let contents = std::fs::read_to_string(context_path.join("Dockerfile"))
+                .context(format!("unable to read Dockerfile in {:#?}", context_path))?;
+            let contents = truncate_to_stage(&contents, target)?;
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar_file
+                .append_data(&mut header, TARGET_DOCKERFILE, contents.as_bytes())
+                .context("unable to append target Dockerfile to tar file")?;
+            dockerfile = TARGET_DOCKERFILE;
+        }
         // Writing to a Vec is infallible, we can unwrap safely
         let tar_file = tar_file.into_inner().unwrap();
 
-        let mut stream = docker.build_image(
+        let stream = docker.build_image(
             BuildImageOptions {
                 t: name,
+                dockerfile,
+                labels: HashMap::from([(NAME_LABEL, name)]),
+                pull: config.pull == PullPolicy::Always,
                 q: false,
                 ..Default::default()
             },
             None,
             Some(tar_file.into()),
         );
+        build_output(name, stream).await
+    }
 
+    /// Waits for a build to finish, failing with the end of its output if it errors
+    async fn build_output(
+        name: &str,
+        mut stream: impl Stream<Item = Result<BuildInfo, DockerError>> + Unpin,
+    ) -> Result<()> {
+        let mut output = LogTail::new(*OUTPUT_TAIL_BYTES);
         while let Some(info) = stream.next().await {
             trace!("{:#?}", info);
+            let info = info.context(format!("unable to build image {}", name))?;
+            if let Some(line) = info.stream {
+                output.push(line.as_bytes());
+            }
+            if let Some(error) = info.error {
+                bail!("{}\n{}", output.finish().trim_end(), error.trim_end());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds an image for several platforms with `docker buildx`, since the
+    /// Docker API can't, and pushes it to `registry`, as the local image store
+    /// can only hold one platform. The image for the local platform is then
+    /// pulled back and tagged as `name` to run it.
+    ///
+    /// Needs the Docker CLI with the buildx plugin, which the hermes image
+    /// ships, logged in to `registry`. Outside of the image they have to be
+    /// installed on the host. Containers can log in by mounting a config at
+    /// `/root/.docker/config.json`, and need a buildx builder that isn't the
+    /// default `docker` driver, which can't build for several platforms.
+    async fn build_multiplatform(
+        docker: &Docker,
+        config: &Config,
+        platforms: &[String],
+        registry: &str,
+        context_path: &Path,
+    ) -> Result<()> {
+        let name = config.name.as_str();
+        let remote_image = [registry, "/", name, ":latest"].concat();
+
+        docker_cli(&["buildx", "version"])
+            .await
+            .context("platforms requires docker buildx")?;
+
+        let platforms = platforms.join(",");
+        // buildx fetches remote contexts itself too
+        let context = match &config.build_context_url {
+            Some(url) => url.into(),
+            None => context_path.to_string_lossy(),
+        };
+        let mut args = vec!["buildx", "build", "--platform", &platforms];
+        args.extend(["--tag", &remote_image, "--push"]);
+        if config.pull == PullPolicy::Always {
+            args.push("--pull");
+        }
+        if let Some(target) = &config.build_target {
+            args.extend(["--target", target]);
+        }
+        args.push(&context);
+        info!("Building {} for {}", remote_image, platforms);
+        docker_cli(&args).await.context(format!(
+            "unable to build image {} for {}",
+            remote_image, platforms
+        ))?;
+
+        docker_cli(&["pull", &remote_image])
+            .await
+            .context(format!("unable to pull image {}", remote_image))?;
+        docker
+            .tag_image(
+                &remote_image,
+                Some(TagImageOptions {
+                    repo: name,
+                    tag: "latest",
+                }),
+            )
+            .await
+            .context(format!("unable to tag image {} as {}", remote_image, name))?;
+
+        Ok(())
+    }
+
+    /// Runs the Docker CLI, failing with the end of its output if it exits
+    /// with an error
+    async fn docker_cli(args: &[&str]) -> Result<()> {
+        let output = Command::new("docker")
+            .args(args)
+            .output()
+            .await
+            .context("unable to run docker")?;
+        trace!("{}", String::from_utf8_lossy(&output.stdout));
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let lines: Vec<_> = stderr.lines().collect();
+            bail!(
+                "docker {} failed: {}",
+                args[0],
+                lines[lines.len().saturating_sub(5)..].join("\n")
+            );
         }
 
         Ok(())
     }
 
-    pub async fn find_containers_with_image(
+    /// Removes the images hermes built for container `name` before its current
+    /// one. An image is kept if it is one of the `keep` most recent, younger
+    /// than `max_age`, or used by a container. Returns how many were removed.
+    pub async fn cleanup_old_images(
+        docker: &Docker,
+        name: &str,
+        keep: Option<usize>,
+        max_age: Option<Duration>,
+    ) -> Result<usize> {
+        let label = [NAME_LABEL, "=", name].concat();
+        let mut images = docker
+            .list_images(Some(ListImagesOptions {
+                filters: HashMap::from([("label", vec![label.as_str()])]),
+                ..Default::default()
+            }))
+            .await
+            .context(format!("unable to list images of {}", name))?;
+        let in_use: HashSet<String> = docker
+            .list_containers(Some(ListContainersOptions::<String> {
+                all: true,
+                ..Default::default()
+            }))
+            .await
+            .context("unable to list containers")?
+            .into_iter()
+            .filter_map(|c| c.image_id)
+            .collect();
+
+        let current = [name, ":latest"].concat();
+        images.retain(|image| !image.repo_tags.contains(&current));
+        images.sort_by_key(|image| Reverse(image.created));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let mut removed = 0;
+        for (idx, image) in images.iter().enumerate() {
+            let recent = keep.is_some_and(|keep| idx < keep);
+            let young = max_age.is_some_and(|age| now - image.created < age.as_secs() as i64);
+            if recent || young || in_use.contains(&image.id) {
+                continue;
+            }
+            if DRY_RUN.load(Ordering::Relaxed) {
+                info!("[dry run] Would remove old image {} of {}", image.id, name);
+                continue;
+            }
+            // Images built from several commits carry a tag for each of them,
+            // which Docker only removes together when forced
+            let options = RemoveImageOptions {
+                force: true,
+                ..Default::default()
+            };
+            docker
+                .remove_image(&image.id, Some(options), None)
+                .await
+                .context(format!("unable to remove image {} of {}", image.id, name))?;
+            info!("Removed old image {} of {}", image.id, name);
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Tags `image` as `repo:tag`
+    pub async fn tag_image(docker: &Docker, image: &str, repo: &str, tag: &str) -> Result<()> {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!("[dry run] Would tag image {} as {}:{}", image, repo, tag);
+            return Ok(());
+        }
+        docker
+            .tag_image(image, Some(TagImageOptions { repo, tag }))
+            .await
+            .context(format!("unable to tag image {} as {}:{}", image, repo, tag))
+    }
+
+    /// The tags of `image`, or `None` if it doesn't exist
+    pub async fn image_tags(docker: &Docker, image: &str) -> Result<Option<Vec<String>>> {
+        match docker.inspect_image(image).await {
+            Ok(inspect) => Ok(Some(inspect.repo_tags.unwrap_or_default())),
+            Err(DockerError::DockerResponseNotFoundError { .. }) => Ok(None),
+            Err(why) => Err(why).context(format!("unable to inspect image {}", image)),
+        }
+    }
+
+    /// The ID of the container named `name`, if there is one
+    pub async fn find_container(docker: &Docker, name: &str) -> Result<Option<String>> {
+        match docker.inspect_container(name, None).await {
+            Ok(container) => Ok(container.id),
+            Err(DockerError::DockerResponseNotFoundError { .. }) => Ok(None),
+            Err(why) => Err(why).context(format!("unable to inspect Docker container {:#?}", name)),
+        }
+    }
+
+    /// Streams the stdout and stderr of a container, or `None` if it doesn't exist
+    pub async fn container_logs(
         docker: &Docker,
         name: &str,
-    ) -> Result<Vec<ContainerSummaryInner>> {
+        tail: String,
+        follow: bool,
+    ) -> Result<Option<impl Stream<Item = Result<Bytes, DockerError>>>> {
+        if find_container(docker, name).await?.is_none() {
+            return Ok(None);
+        }
+
+        let options = LogsOptions {
+            follow,
+            stdout: true,
+            stderr: true,
+            tail,
+            ..Default::default()
+        };
+        Ok(Some(
+            docker
+                .logs(name, Some(options))
+                .map(|chunk| chunk.map(LogOutput::into_bytes)),
+        ))
+    }
+
+    /// Streams the IDs and config names of managed containers as they start
+    pub fn container_starts(
+        docker: &Docker,
+    ) -> impl Stream<Item = Result<(String, String), DockerError>> {
+        let options = EventsOptions {
+            filters: HashMap::from([
+                ("type", vec!["container"]),
+                ("event", vec!["start"]),
+                ("label", vec![NAME_LABEL]),
+            ]),
+            ..Default::default()
+        };
+        docker.events(Some(options)).filter_map(|event| async move {
+            match event {
+                Ok(event) => {
+                    // Events carry the labels of their container
+                    let actor = event.actor?;
+                    let name = actor.attributes?.remove(NAME_LABEL)?;
+                    Some(Ok((actor.id?, name)))
+                }
+                Err(why) => Some(Err(why)),
+            }
+        })
+    }
+
+    /// Lists every container created by hermes, running or not
+    pub async fn list_managed_containers(docker: &Docker) -> Result<Vec<ContainerSummaryInner>> {
+        let label = [MANAGED_LABEL, "=true"].concat();
         let lco = ListContainersOptions {
+            all: true,
             filters: {
                 let mut filters = HashMap::new();
-                filters.insert("ancestor", vec![name]);
+                filters.insert("label", vec![label.as_str()]);
                 filters
             },
             ..Default::default()
@@ -137,65 +1303,1883 @@ pub mod docker {
         docker
             .list_containers(Some(lco))
             .await
-            .context(format!("unable to list containers with image {}", name))
+            .context("unable to list managed containers")
     }
 
-    pub async fn stop_container(docker: &Docker, name: &str) -> Result<()> {
-        docker
-            .stop_container(&name, None)
+    /// Finds the container hermes created for the config `name`, running or not
+    pub async fn find_managed_container(
+        docker: &Docker,
+        name: &str,
+    ) -> Result<Option<ContainerSummaryInner>> {
+        let label = [NAME_LABEL, "=", name].concat();
+        let lco = ListContainersOptions {
+            all: true,
+            filters: {
+                let mut filters = HashMap::new();
+                filters.insert("label", vec![label.as_str()]);
+                filters
+            },
+            ..Default::default()
+        };
+        let containers = docker
+            .list_containers(Some(lco))
             .await
-            .context(format!("unable to stop Docker container {:#?}", name))?;
+            .context(format!("unable to list containers for {}", name))?;
+
+        Ok(containers.into_iter().next())
+    }
+
+    /// Stops and removes a container, waiting `timeout` seconds before killing it
+    pub async fn stop_container(docker: &Docker, name: &str, timeout: Option<i64>) -> Result<()> {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!("[dry run] Would stop and remove container {}", name);
+            return Ok(());
+        }
+
+        halt_container(docker, name, timeout).await?;
+        remove_container(docker, name).await
+    }
+
+    /// Stops a container, keeping it around so it can be started again
+    pub async fn halt_container(docker: &Docker, name: &str, timeout: Option<i64>) -> Result<()> {
         docker
-            .remove_container(&name, None)
+            .stop_container(name, timeout.map(|t| StopContainerOptions { t }))
             .await
-            .context(format!("unable to remove Docker container {:#?}", name))?;
+            .context(format!("unable to stop Docker container {:#?}", name))
+    }
 
-        Ok(())
+    pub async fn remove_container(docker: &Docker, name: &str) -> Result<()> {
+        docker
+            .remove_container(name, None)
+            .await
+            .context(format!("unable to remove Docker container {:#?}", name))
     }
 
-    pub async fn run_container(docker: &Docker, config: Config) -> Result<()> {
-        let image = docker
-            .inspect_image(&config.name)
+    pub async fn rename_container(docker: &Docker, name: &str, new_name: &str) -> Result<()> {
+        docker
+            .rename_container(name, RenameContainerOptions { name: new_name })
             .await
-            .context(format!("unable to inspect Docker image {:#?}", config.name))?;
-        let image_config = image.config.unwrap_or_else(Default::default);
-        let cc = ContainerConfig {
-            cmd: image_config.cmd,
-            entrypoint: image_config.entrypoint,
-            working_dir: image_config.working_dir,
-            image: Some(
-                image
-                    .repo_tags
-                    .map(|mut t| t.pop())
-                    .flatten()
-                    .unwrap_or(image.id),
-            ),
-            env: config.env,
+            .context(format!(
+                "unable to rename Docker container {:#?} to {:#?}",
+                name, new_name
+            ))
+    }
+
+    /// Whether a container is done starting: healthy, or running if it has no
+    /// health check. Fails if it stopped or became unhealthy.
+    fn is_healthy(state: &ContainerState) -> Result<bool> {
+        if state.running != Some(true) {
+            bail!("container is not running");
+        }
+        match state.health.as_ref().and_then(|health| health.status) {
+            Some(HealthStatusEnum::UNHEALTHY) => bail!("container is unhealthy"),
+            Some(HealthStatusEnum::STARTING) => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Waits until the container `name` is healthy, see [`is_healthy`]
+    pub async fn wait_healthy(docker: &Docker, name: &str) -> Result<()> {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let wait = async {
+            loop {
+                let state = docker
+                    .inspect_container(name, None)
+                    .await
+                    .context(format!("unable to inspect Docker container {:#?}", name))?
+                    .state
+                    .unwrap_or_default();
+                if is_healthy(&state).context(format!("{} failed to start", name))? {
+                    return Ok(());
+                }
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        };
+        time::timeout(*HEALTH_TIMEOUT, wait)
+            .await
+            .map_err(|_| anyhow!("{} not healthy after {:?}", name, *HEALTH_TIMEOUT))?
+    }
+
+    type Args = Option<Vec<String>>;
+
+    /// The entrypoint and command of a container, preferring the config's over the image's.
+    /// Like `docker run --entrypoint`, overriding the entrypoint drops the image's command.
+    fn entrypoint_and_cmd(
+        entrypoint: Args,
+        cmd: Args,
+        image_entrypoint: Args,
+        image_cmd: Args,
+    ) -> (Args, Args) {
+        match entrypoint {
+            Some(entrypoint) => (Some(entrypoint), cmd),
+            None => (image_entrypoint, cmd.or(image_cmd)),
+        }
+    }
+
+    /// The extra hosts and resolvers of `config`, shared by its container and hooks
+    fn network_host_config(config: &Config) -> HostConfig {
+        HostConfig {
+            extra_hosts: config.extra_hosts.clone(),
+            dns: config.dns.clone(),
+            dns_search: config.dns_search.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Runs `cmd` to completion in a one-off container from the image of `config`,
+    /// logging its output. Fails if the command exits with a non-zero status.
+    pub async fn run_hook(
+        docker: &Docker,
+        config: &Config,
+        hook: &str,
+        cmd: &[String],
+    ) -> Result<()> {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!(
+                "[dry run] Would run {} hook {:?} for {}",
+                hook, cmd, config.name
+            );
+            return Ok(());
+        }
+
+        let cc = ContainerConfig {
+            cmd: Some(cmd.to_vec()),
+            image: Some(config.name.clone()),
+            user: config.user.clone(),
+            working_dir: config.workdir.clone(),
+            env: config.resolved_env().await?,
             host_config: Some(HostConfig {
-                binds: config.volumes,
-                port_bindings: config.ports,
-                restart_policy: config.restart,
-                ..Default::default()
+                binds: config.volumes.clone(),
+                mounts: config.all_mounts(),
+                tmpfs: config.tmpfs.clone(),
+                // Hooks see the same filesystem as the container they run for
+                readonly_rootfs: Some(config.read_only),
+                ..network_host_config(config)
             }),
             ..Default::default()
         };
-
         let id = docker
             .create_container(None::<CreateContainerOptions<String>>, cc)
             .await
             .context(format!(
-                "unable to create Docker container {:#?}",
-                config.name
+                "unable to create {} hook container for {}",
+                hook, config.name
             ))?
             .id;
-        docker
-            .start_container(&id, None::<StartContainerOptions<String>>)
-            .await
-            .context(format!(
-                "unable to start Docker container {:#?}",
+
+        let status = async {
+            docker
+                .start_container(&id, None::<StartContainerOptions<String>>)
+                .await
+                .context(format!("unable to start {} hook container {}", hook, id))?;
+            let status = match docker
+                .wait_container(&id, None::<WaitContainerOptions<String>>)
+                .next()
+                .await
+            {
+                Some(res) => {
+                    res.context(format!("unable to wait for {} hook container {}", hook, id))?
+                        .status_code
+                }
+                None => bail!("{} hook container {} vanished before exiting", hook, id),
+            };
+
+            let options = LogsOptions::<String> {
+                stdout: true,
+                stderr: true,
+                ..Default::default()
+            };
+            let mut logs = docker.logs(&id, Some(options));
+            while let Some(chunk) = logs.next().await {
+                match chunk {
+                    Ok(chunk) => info!(
+                        "[{} {}] {}",
+                        config.name,
+                        hook,
+                        chunk.to_string().trim_end()
+                    ),
+                    Err(why) => error!(
+                        "Failed to read {} hook logs for {}: {}",
+                        hook, config.name, why
+                    ),
+                }
+            }
+
+            Ok(status)
+        }
+        .await;
+
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        if let Err(why) = docker.remove_container(&id, Some(options)).await {
+            error!("Failed to remove {} hook container {}: {}", hook, id, why);
+        }
+
+        match status? {
+            0 => Ok(()),
+            code => bail!(
+                "{} hook for {} exited with status {}",
+                hook,
+                config.name,
+                code
+            ),
+        }
+    }
+
+    pub async fn run_container(docker: &Docker, config: &Config) -> Result<()> {
+        if config.docker_socket {
+            warn!(
+                "Container {} has the Docker socket mounted, which gives it root access to the host",
                 config.name
-            ))?;
+            );
+        }
+        if DRY_RUN.load(Ordering::Relaxed) {
+            info!(
+                "[dry run] Would run container {} from image {} with env {:?}, binds {:?}, mounts {:?} and ports {:?}",
+                config.name,
+                config.name,
+                redacted_env(&config.env),
+                config.volumes,
+                config.all_mounts(),
+                config.ports
+            );
+            return Ok(());
+        }
+
+        // Recreating a container can briefly race the old one for its name or
+        // ports, which only needs another try
+        let mut attempt = 1;
+        loop {
+            match create_and_start(docker, config).await {
+                Ok(()) => return Ok(()),
+                Err(why) if attempt < *START_ATTEMPTS && is_transient(&why) => {
+                    warn!(
+                        "Attempt {}/{} to start {} failed, retrying in {:?}: {:#}",
+                        attempt, *START_ATTEMPTS, config.name, *START_RETRY_DELAY, why
+                    );
+                    time::sleep(*START_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                Err(why) => return Err(why),
+            }
+        }
+    }
+
+    /// Whether a failure to start a container is a race that can resolve itself,
+    /// as opposed to something like a missing image that fails every time
+    fn is_transient(why: &anyhow::Error) -> bool {
+        let message = format!("{:#}", why);
+        [
+            "already in use",
+            "port is already allocated",
+            "address already in use",
+        ]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+    }
+
+    async fn create_and_start(docker: &Docker, config: &Config) -> Result<()> {
+        let id = create_container(docker, config).await?;
+        let started = start_container(docker, &id).await;
+        if started.is_err() {
+            // A retry creates the container again, under the same name
+            if let Err(why) = remove_container(docker, &id).await {
+                warn!(
+                    "Failed to remove container {} that didn't start: {:#}",
+                    id, why
+                );
+            }
+        }
+        if config.gpus.is_some() {
+            // Docker only resolves device requests when starting the container
+            started.context(format!(
+                "{} requests GPUs, make sure the Docker daemon has the NVIDIA container toolkit",
+                config.name
+            ))
+        } else {
+            started
+        }
+    }
+
+    /// Makes sure the container of `config` is running with its current settings.
+    ///
+    /// A container created from a different config or image is recreated, an
+    /// up to date one is only started if it isn't running already.
+    pub async fn sync_container(docker: &Docker, config: &Config) -> Result<()> {
+        // Comparing against the existing container already needs Docker
+        if DRY_RUN.load(Ordering::Relaxed) {
+            return run_container(docker, config).await;
+        }
+        let existing = match find_managed_container(docker, &config.name).await? {
+            Some(existing) => existing,
+            None => return run_container(docker, config).await,
+        };
+        // Containers are only ever listed with an ID, we can unwrap safely
+        let id = existing.id.unwrap();
+        let running = existing.state.as_deref() == Some("running");
+
+        let cc = container_config(docker, config).await?;
+        let hash = cc.labels.as_ref().and_then(|l| l.get(CONFIG_HASH_LABEL));
+        if existing
+            .labels
+            .as_ref()
+            .and_then(|l| l.get(CONFIG_HASH_LABEL))
+            == hash
+        {
+            if running {
+                info!("Container {} is up to date", config.name);
+                return Ok(());
+            }
+            return start_container(docker, &id).await;
+        }
+
+        info!("Recreating container {}, its config changed", config.name);
+        if running {
+            stop_container(docker, &id, config.stop_timeout).await?;
+        } else {
+            remove_container(docker, &id).await?;
+        }
+        run_container(docker, config).await
+    }
+
+    /// Creates the container for `config` without starting it, returning its ID
+    pub async fn create_container(docker: &Docker, config: &Config) -> Result<String> {
+        if config.privileged {
+            warn!(
+                "Creating {} in privileged mode, it has full access to the host",
+                config.name
+            );
+        }
+
+        if let (Some(network), Some(ip)) = (&config.network, config.ip) {
+            check_subnet(docker, network, ip).await?;
+        }
+
+        let cc = container_config(docker, config).await?;
+        let options = CreateContainerOptions {
+            name: config.name.as_str(),
+        };
+        let id = match docker.create_container(Some(options), cc).await {
+            Ok(res) => res.id,
+            // Creating a container only conflicts when its name is taken
+            Err(DockerError::DockerResponseConflictError { .. }) => {
+                let owner = docker
+                    .inspect_container(&config.name, None)
+                    .await
+                    .ok()
+                    .and_then(|c| c.id)
+                    .unwrap_or_else(|| "an unknown container".to_string());
+                bail!("container name {} already in use by {}", config.name, owner);
+            }
+            Err(why) => {
+                return Err(why).context(format!(
+                    "unable to create Docker container {:#?}",
+                    config.name
+                ))
+            }
+        };
+
+        Ok(id)
+    }
+
+    /// Checks that `ip` is in one of the subnets of `network`, if it has any
+    /// for its IP version
+    async fn check_subnet(docker: &Docker, network: &str, ip: IpAddr) -> Result<()> {
+        let inspected = docker
+            .inspect_network(network, None::<InspectNetworkOptions<String>>)
+            .await
+            .context(format!("unable to inspect Docker network {:#?}", network))?;
+        let subnets: Vec<IpNet> = inspected
+            .ipam
+            .and_then(|ipam| ipam.config)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|config| config.get("Subnet")?.parse().ok())
+            .filter(|subnet| matches!(subnet, IpNet::V4(_)) == ip.is_ipv4())
+            .collect();
+        if !subnets.is_empty() && !subnets.iter().any(|subnet| subnet.contains(&ip)) {
+            bail!("ip {} is outside the subnets of network {}", ip, network);
+        }
 
         Ok(())
     }
+
+    /// The settings of the container for `config`, labelled with a hash of themselves
+    async fn container_config(docker: &Docker, config: &Config) -> Result<ContainerConfig<String>> {
+        let image = docker
+            .inspect_image(&config.name)
+            .await
+            .context(format!("unable to inspect Docker image {:#?}", config.name))?;
+        let image_id = image.id.clone();
+        let image_config = image.config.unwrap_or_else(Default::default);
+        let mut labels = config.labels.clone().unwrap_or_default();
+        // The hash is only filled in once the rest of the settings are known
+        for (key, value) in [
+            (MANAGED_LABEL, "true"),
+            (NAME_LABEL, config.name.as_str()),
+            (CONFIG_HASH_LABEL, ""),
+        ] {
+            if labels.insert(key.to_string(), value.to_string()).is_some() {
+                warn!(
+                    "Ignoring label {} of {}, it is set by hermes",
+                    key, config.name
+                );
+            }
+        }
+        let (entrypoint, cmd) = entrypoint_and_cmd(
+            config.entrypoint.clone(),
+            config.command.clone(),
+            image_config.entrypoint,
+            image_config.cmd,
+        );
+        let mut cc = ContainerConfig {
+            cmd,
+            entrypoint,
+            working_dir: config.workdir.clone().or(image_config.working_dir),
+            hostname: config.hostname.clone(),
+            user: config.user.clone(),
+            domainname: config.domainname.clone(),
+            image: Some(
+                image
+                    .repo_tags
+                    .and_then(|mut t| t.pop())
+                    .unwrap_or(image.id),
+            ),
+            env: config.resolved_env().await?,
+            labels: Some(labels),
+            stop_timeout: config.stop_timeout,
+            stop_signal: config.stop_signal.clone(),
+            networking_config: config.network.as_ref().map(|network| NetworkingConfig {
+                endpoints_config: HashMap::from([(
+                    network.clone(),
+                    EndpointSettings {
+                        aliases: config.network_aliases.clone(),
+                        ipam_config: config.ip.map(|ip| EndpointIpamConfig {
+                            ipv4_address: ip.is_ipv4().then(|| ip.to_string()),
+                            ipv6_address: ip.is_ipv6().then(|| ip.to_string()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )]),
+            }),
+            host_config: Some(HostConfig {
+                binds: config.volumes.clone(),
+                mounts: config.all_mounts(),
+                port_bindings: config.ports.clone(),
+                restart_policy: config.restart.clone(),
+                tmpfs: config.tmpfs.clone(),
+                device_requests: config.gpus.clone().map(|gpus| vec![gpus]),
+                devices: config.devices.clone(),
+                network_mode: config.network.clone(),
+                cap_add: config.cap_add.clone(),
+                cap_drop: config.cap_drop.clone(),
+                privileged: Some(config.privileged),
+                ulimits: config.ulimits.clone(),
+                sysctls: config.sysctls.clone(),
+                blkio_weight: config.blkio_weight,
+                pids_limit: config.pids_limit,
+                init: config.init,
+                memory: config.memory,
+                memory_swap: config.memory_swap,
+                oom_kill_disable: Some(config.oom_kill_disable),
+                // Writable paths come from `tmpfs` and `volumes`
+                readonly_rootfs: Some(config.read_only),
+                ..network_host_config(config)
+            }),
+            ..Default::default()
+        };
+
+        // The hash covers the image ID, so rebuilding the image counts as a change too
+        let settings = serde_json::to_value((&cc, &image_id)).unwrap().to_string();
+        let hash = hex::encode(Hash::hash(settings.as_bytes()));
+        if let Some(labels) = cc.labels.as_mut() {
+            labels.insert(CONFIG_HASH_LABEL.to_string(), hash);
+        }
+
+        Ok(cc)
+    }
+
+    pub async fn start_container(docker: &Docker, name: &str) -> Result<()> {
+        docker
+            .start_container(name, None::<StartContainerOptions<String>>)
+            .await
+            .context(format!("unable to start Docker container {:#?}", name))
+    }
+
+    /// Runs hermes in takeover mode in a detached, self-removing container from
+    /// the new image, so it can replace the current instance once it exits
+    pub async fn spawn_takeover(docker: &Docker, config: &Config) -> Result<String> {
+        let cc = ContainerConfig {
+            cmd: Some(vec!["--takeover".to_string()]),
+            entrypoint: config.entrypoint.clone(),
+            image: Some(config.name.clone()),
+            env: config.resolved_env().await?,
+            labels: Some(HashMap::from([(
+                MANAGED_LABEL.to_string(),
+                "true".to_string(),
+            )])),
+            host_config: Some(HostConfig {
+                binds: config.volumes.clone(),
+                mounts: config.all_mounts(),
+                auto_remove: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let id = docker
+            .create_container(None::<CreateContainerOptions<String>>, cc)
+            .await
+            .context("unable to create takeover container")?
+            .id;
+        start_container(docker, &id).await?;
+
+        Ok(id)
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+        use bollard::{
+            models::{ContainerInspectResponse, Health, Image, ImageSummary},
+            API_DEFAULT_VERSION,
+        };
+        use hyper::{
+            header::CONTENT_TYPE,
+            service::{make_service_fn, service_fn},
+            Body, Method, Request, Response, Server, StatusCode,
+        };
+        use std::{convert::Infallible, sync::Arc};
+
+        /// Serves the Docker API with `reply`, which answers the method, path and
+        /// query of each request, without the API version, with a status and a body
+        pub(crate) fn mock_docker<F>(reply: F) -> Docker
+        where
+            F: Fn(&Method, &str, &str) -> (StatusCode, String) + Send + Sync + 'static,
+        {
+            mock_docker_with_body(move |method, path, query, _| reply(method, path, query))
+        }
+
+        /// Like [`mock_docker`], also passing `reply` the body of each request
+        pub(crate) fn mock_docker_with_body<F>(reply: F) -> Docker
+        where
+            F: Fn(&Method, &str, &str, &[u8]) -> (StatusCode, String) + Send + Sync + 'static,
+        {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let reply = Arc::new(reply);
+            let service = make_service_fn(move |_| {
+                let reply = reply.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let reply = reply.clone();
+                        async move {
+                            let (parts, body) = req.into_parts();
+                            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                            let path = parts.uri.path();
+                            let path = match path.strip_prefix("/v") {
+                                Some(versioned) => &versioned[versioned.find('/').unwrap_or(0)..],
+                                None => path,
+                            };
+                            let query = parts.uri.query().unwrap_or_default();
+                            let (status, body) = reply(&parts.method, path, query, &body);
+                            let response = Response::builder()
+                                .status(status)
+                                .header(CONTENT_TYPE, "application/json")
+                                .body(Body::from(body));
+                            Ok::<_, Infallible>(response.unwrap())
+                        }
+                    }))
+                }
+            });
+            tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+            Docker::connect_with_http(&format!("http://{}", addr), 5, API_DEFAULT_VERSION).unwrap()
+        }
+
+        pub(crate) fn json<T: serde::Serialize>(
+            status: StatusCode,
+            value: &T,
+        ) -> (StatusCode, String) {
+            (status, serde_json::to_string(value).unwrap())
+        }
+
+        pub(crate) fn message(status: StatusCode, message: &str) -> (StatusCode, String) {
+            json(status, &serde_json::json!({ "message": message }))
+        }
+
+        async fn config(contents: &str) -> Config {
+            Config::parse("app".to_string(), contents, None).unwrap()
+        }
+
+        /// A user-defined network with an IPv4 subnet
+        fn backend() -> serde_json::Value {
+            serde_json::json!({
+                "Name": "backend",
+                "IPAM": { "Config": [{ "Subnet": "10.1.0.0/16" }] },
+            })
+        }
+
+        /// The body of the create request `run_container` sends for `config`
+        async fn created_container(config: &Config) -> serde_json::Value {
+            let created = Arc::new(std::sync::Mutex::new(None));
+            let docker = mock_docker_with_body({
+                let created = created.clone();
+                move |method, path, _, body| match (method, path) {
+                    (&Method::GET, "/images/app/json") => json(
+                        StatusCode::OK,
+                        &Image {
+                            id: "sha256:1234".to_string(),
+                            ..Default::default()
+                        },
+                    ),
+                    (&Method::POST, "/containers/create") => {
+                        *created.lock().unwrap() = Some(serde_json::from_slice(body).unwrap());
+                        json(
+                            StatusCode::CREATED,
+                            &serde_json::json!({ "Id": "0123abcd", "Warnings": [] }),
+                        )
+                    }
+                    (&Method::POST, "/containers/0123abcd/start") => {
+                        (StatusCode::NO_CONTENT, String::new())
+                    }
+                    (&Method::GET, "/networks/backend") => json(StatusCode::OK, &backend()),
+                    _ => message(StatusCode::NOT_FOUND, "not found"),
+                }
+            });
+            run_container(&docker, config).await.unwrap();
+            let created = created.lock().unwrap().take();
+            created.unwrap()
+        }
+
+        const MULTI_STAGE: &str =
+            "FROM rust AS builder\nRUN cargo build\n\nFROM debian AS runtime\nCOPY . .\n";
+
+        #[test]
+        fn dockerfiles_are_cut_after_the_target_stage() {
+            assert_eq!(
+                truncate_to_stage(MULTI_STAGE, "builder").unwrap(),
+                "FROM rust AS builder\nRUN cargo build\n\n"
+            );
+            assert_eq!(
+                truncate_to_stage(MULTI_STAGE, "RUNTIME").unwrap(),
+                MULTI_STAGE
+            );
+            assert!(truncate_to_stage(MULTI_STAGE, "test").is_err());
+        }
+
+        /// The query and tar context `build_image` sends for `config`
+        async fn built_image(config: &Config) -> (String, Vec<u8>) {
+            let repo = std::env::temp_dir()
+                .join(["hermes-test-build-", &uuid::Uuid::new_v4().to_string()].concat());
+            let context = config.build_context(&repo);
+            std::fs::create_dir_all(&context).unwrap();
+            std::fs::write(repo.join("README.md"), "# app").unwrap();
+            std::fs::write(context.join("Dockerfile"), MULTI_STAGE).unwrap();
+
+            let built = Arc::new(std::sync::Mutex::new(None));
+            let docker = mock_docker_with_body({
+                let built = built.clone();
+                move |method, path, query, body| match (method, path) {
+                    (&Method::POST, "/build") => {
+                        *built.lock().unwrap() = Some((query.to_string(), body.to_vec()));
+                        (StatusCode::OK, "{}".to_string())
+                    }
+                    _ => message(StatusCode::NOT_FOUND, "not found"),
+                }
+            });
+            build_image(&docker, config, &context).await.unwrap();
+            std::fs::remove_dir_all(&repo).unwrap();
+            let built = built.lock().unwrap().take();
+            built.unwrap()
+        }
+
+        #[tokio::test]
+        async fn build_targets_select_a_dockerfile_stage() {
+            let config = config("url = \"/dev/null\"\nbuild_target = \"builder\"").await;
+            let (query, context) = built_image(&config).await;
+            assert!(query.contains("dockerfile=.hermes.Dockerfile"), "{}", query);
+
+            let mut context = tar::Archive::new(context.as_slice());
+            let mut dockerfile = context
+                .entries()
+                .unwrap()
+                .map(Result::unwrap)
+                .find(|entry| entry.path().unwrap().ends_with(TARGET_DOCKERFILE))
+                .unwrap();
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut dockerfile, &mut contents).unwrap();
+            assert_eq!(contents, "FROM rust AS builder\nRUN cargo build\n\n");
+        }
+
+        #[tokio::test]
+        async fn subdirs_are_built_on_their_own() {
+            let paths = |context: Vec<u8>| {
+                let mut context = tar::Archive::new(context.as_slice());
+                let entries = context.entries().unwrap().map(Result::unwrap);
+                entries
+                    .map(|entry| entry.path().unwrap().to_string_lossy().into_owned())
+                    .filter(|path| path != "./")
+                    .collect::<Vec<_>>()
+            };
+            let (_, context) = built_image(&config("url = \"/dev/null\"").await).await;
+            let mut root = paths(context);
+            root.sort();
+            assert_eq!(root, ["Dockerfile", "README.md"]);
+
+            let config = config("url = \"/dev/null\"\nsubdir = \"services/api\"").await;
+            let (_, context) = built_image(&config).await;
+            assert_eq!(paths(context), ["Dockerfile"]);
+        }
+
+        #[tokio::test]
+        async fn pull_always_pulls_base_images_on_build() {
+            let always = config("url = \"/dev/null\"\npull = \"always\"").await;
+            let (query, _) = built_image(&always).await;
+            assert!(query.contains("pull=true"), "{}", query);
+
+            let (query, _) = built_image(&config("url = \"/dev/null\"").await).await;
+            assert!(!query.contains("pull=true"), "{}", query);
+        }
+
+        #[tokio::test]
+        async fn pull_never_needs_local_base_images() {
+            let docker = mock_docker(|_, path, _| match path {
+                "/images/rust/json" => json(StatusCode::OK, &Image::default()),
+                _ => message(StatusCode::NOT_FOUND, "No such image"),
+            });
+            let repo =
+                std::env::temp_dir().join(format!("hermes-test-never-{}", std::process::id()));
+            std::fs::create_dir_all(&repo).unwrap();
+            std::fs::write(repo.join("Dockerfile"), MULTI_STAGE).unwrap();
+            let config = config("url = \"/dev/null\"\npull = \"never\"").await;
+            let why = build_image(&docker, &config, &repo).await.unwrap_err();
+            std::fs::remove_dir_all(&repo).unwrap();
+            assert_eq!(
+                why.to_string(),
+                "base image debian isn't available locally and pull is never"
+            );
+        }
+
+        #[test]
+        fn base_images_leave_out_stages_and_scratch() {
+            let dockerfile = "FROM --platform=$BUILDPLATFORM rust:1.70 AS builder\n\
+                from builder as tested\nFROM scratch\nFROM ${BASE}\nFROM alpine\n";
+            assert_eq!(base_images(dockerfile), ["rust:1.70", "alpine"]);
+        }
+
+        #[tokio::test]
+        async fn remote_contexts_are_fetched_by_docker() {
+            let config = config(
+                "url = \"/dev/null\"\nbuild_context_url = \"https://example.com/app.tar.gz\"",
+            )
+            .await;
+            let (query, context) = built_image(&config).await;
+            assert!(
+                query.contains("remote=https%3A%2F%2Fexample.com%2Fapp.tar.gz"),
+                "{}",
+                query
+            );
+            assert!(context.is_empty());
+        }
+
+        #[tokio::test]
+        async fn failed_builds_only_report_the_end_of_their_output() {
+            crate::tests::test_env();
+            let docker = mock_docker(|method, path, _| match (method, path) {
+                (&Method::POST, "/build") => {
+                    let line = serde_json::json!({ "stream": "x".repeat(1023) + "\n" });
+                    let mut body = (line.to_string() + "\n").repeat(256);
+                    body.push_str(
+                        "{\"stream\": \"last words\\n\"}\n{\"error\": \"build failed\"}\n",
+                    );
+                    (StatusCode::OK, body)
+                }
+                _ => message(StatusCode::NOT_FOUND, "not found"),
+            });
+            let repo =
+                std::env::temp_dir().join(format!("hermes-test-huge-{}", std::process::id()));
+            std::fs::create_dir_all(&repo).unwrap();
+            std::fs::write(repo.join("Dockerfile"), MULTI_STAGE).unwrap();
+            let why = build_image(&docker, &config("url = \"/dev/null\"").await, &repo)
+                .await
+                .unwrap_err()
+                .to_string();
+            std::fs::remove_dir_all(&repo).unwrap();
+
+            // 256KiB of output, of which the default LOG_MAX_KB keeps 64KiB
+            assert!(
+                why.starts_with(&format!(
+                    "[{} bytes truncated]\n",
+                    256 * 1024 + 11 - 64 * 1024
+                )),
+                "{}",
+                &why[..100]
+            );
+            assert!(
+                why.ends_with("last words\nbuild failed"),
+                "{}",
+                &why[why.len() - 100..]
+            );
+            assert!(why.len() < 65 * 1024);
+        }
+
+        #[tokio::test]
+        async fn builds_without_a_target_use_the_repo_dockerfile() {
+            let (query, _) = built_image(&config("url = \"/dev/null\"").await).await;
+            assert!(!query.contains(TARGET_DOCKERFILE), "{}", query);
+        }
+
+        // Needs docker buildx and a registry it can push to, like `registry:2`
+        // on `localhost:5000` given in `HERMES_TEST_REGISTRY`
+        #[tokio::test]
+        async fn multiplatform_builds_run_the_local_platform() {
+            let registry = match std::env::var("HERMES_TEST_REGISTRY") {
+                Ok(registry) if docker_cli(&["buildx", "version"]).await.is_ok() => registry,
+                _ => return,
+            };
+            let dir =
+                std::env::temp_dir().join(format!("hermes-test-buildx-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("Dockerfile"), "FROM busybox\nCMD [\"true\"]\n").unwrap();
+            let config = config(&format!(
+                "url = \"/dev/null\"\nplatforms = [\"linux/amd64\", \"linux/arm64\"]\n\
+                registry = \"{}\"",
+                registry
+            ))
+            .await;
+            let docker = Docker::connect_with_local_defaults().unwrap();
+            build_image(&docker, &config, &dir).await.unwrap();
+            assert!(docker.inspect_image("app").await.is_ok());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        /// The requests `sync_container` makes for `config`, with a running
+        /// container that was created from `existing`
+        async fn synced_container(existing: &Config, config: &Config) -> Vec<String> {
+            let image = |method: &Method, path: &str| match (method, path) {
+                (&Method::GET, "/images/app/json") => Some(json(
+                    StatusCode::OK,
+                    &Image {
+                        id: "sha256:1234".to_string(),
+                        ..Default::default()
+                    },
+                )),
+                _ => None,
+            };
+            let docker = mock_docker(move |method, path, _| {
+                image(method, path).unwrap_or_else(|| message(StatusCode::NOT_FOUND, "not found"))
+            });
+            let cc = container_config(&docker, existing).await.unwrap();
+
+            let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let docker = mock_docker({
+                let requests = requests.clone();
+                move |method, path, _| {
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {}", method, path));
+                    if let Some(reply) = image(method, path) {
+                        return reply;
+                    }
+                    match (method, path) {
+                        (&Method::GET, "/containers/json") => json(
+                            StatusCode::OK,
+                            &[ContainerSummaryInner {
+                                id: Some("0123abcd".to_string()),
+                                state: Some("running".to_string()),
+                                labels: cc.labels.clone(),
+                                ..Default::default()
+                            }],
+                        ),
+                        (&Method::POST, "/containers/create") => json(
+                            StatusCode::CREATED,
+                            &serde_json::json!({ "Id": "4567cdef", "Warnings": [] }),
+                        ),
+                        _ => (StatusCode::NO_CONTENT, String::new()),
+                    }
+                }
+            });
+            sync_container(&docker, config).await.unwrap();
+            let requests = requests.lock().unwrap();
+            requests.clone()
+        }
+
+        #[tokio::test]
+        async fn unchanged_containers_are_left_running() {
+            let config = config("url = \"/dev/null\"\nenv = { A = \"1\", B = \"2\" }").await;
+            let requests = synced_container(&config, &config).await;
+            assert_eq!(requests, ["GET /containers/json", "GET /images/app/json"]);
+        }
+
+        #[tokio::test]
+        async fn changed_containers_are_recreated() {
+            let existing = config("url = \"/dev/null\"\nenv = { A = \"1\" }").await;
+            let changed = config("url = \"/dev/null\"\nenv = { A = \"2\" }").await;
+            let requests = synced_container(&existing, &changed).await;
+            assert_eq!(
+                requests[2..],
+                [
+                    "POST /containers/0123abcd/stop",
+                    "DELETE /containers/0123abcd",
+                    "GET /images/app/json",
+                    "POST /containers/create",
+                    "POST /containers/4567cdef/start",
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn pings_retry_until_the_daemon_is_back() {
+            let pings = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let docker = mock_docker({
+                let pings = pings.clone();
+                move |_, path, _| match path {
+                    "/_ping" if pings.fetch_add(1, Ordering::SeqCst) < 2 => {
+                        message(StatusCode::INTERNAL_SERVER_ERROR, "restarting")
+                    }
+                    "/_ping" => (StatusCode::OK, "OK".to_string()),
+                    _ => message(StatusCode::NOT_FOUND, "not found"),
+                }
+            });
+            let backoff = Duration::from_millis(10);
+            ping_with_backoff(&docker, 3, backoff).await.unwrap();
+            assert_eq!(pings.load(Ordering::SeqCst), 3);
+
+            pings.store(0, Ordering::SeqCst);
+            let why = ping_with_backoff(&docker, 2, backoff).await.unwrap_err();
+            assert_eq!(
+                why.to_string(),
+                "Docker daemon unreachable after 2 attempts"
+            );
+        }
+
+        #[tokio::test]
+        async fn name_conflicts_report_the_container_holding_the_name() {
+            let docker = mock_docker(|method, path, _| match (method, path) {
+                (&Method::GET, "/images/app/json") => json(
+                    StatusCode::OK,
+                    &Image {
+                        id: "sha256:1234".to_string(),
+                        ..Default::default()
+                    },
+                ),
+                (&Method::POST, "/containers/create") => message(StatusCode::CONFLICT, "Conflict."),
+                (&Method::GET, "/containers/app/json") => json(
+                    StatusCode::OK,
+                    &ContainerInspectResponse {
+                        id: Some("0123abcd".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                _ => message(StatusCode::NOT_FOUND, "not found"),
+            });
+            let why = run_container(&docker, &config("url = \"/dev/null\"").await)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                why.to_string(),
+                "container name app already in use by 0123abcd"
+            );
+        }
+
+        #[tokio::test]
+        async fn containers_are_found_by_name() {
+            let docker = mock_docker(|_, path, _| match path {
+                "/containers/app/json" => json(
+                    StatusCode::OK,
+                    &ContainerInspectResponse {
+                        id: Some("0123abcd".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                _ => message(StatusCode::NOT_FOUND, "No such container"),
+            });
+            let found = find_container(&docker, "app").await.unwrap();
+            assert_eq!(found.as_deref(), Some("0123abcd"));
+            assert_eq!(find_container(&docker, "missing").await.unwrap(), None);
+        }
+
+        #[tokio::test]
+        async fn starts_are_streamed_as_ids_and_names() {
+            let docker = mock_docker(|_, path, query| match path {
+                "/events" => {
+                    assert!(query.contains("start"), "{}", query);
+                    let event = |id: &str, attributes: serde_json::Value| {
+                        serde_json::json!({
+                            "Type": "container",
+                            "Action": "start",
+                            "Actor": { "ID": id, "Attributes": attributes },
+                        })
+                        .to_string()
+                    };
+                    let events = [
+                        event("0123abcd", serde_json::json!({ (NAME_LABEL): "app" })),
+                        event("4567cdef", serde_json::json!({})),
+                        event("89abcdef", serde_json::json!({ (NAME_LABEL): "api" })),
+                    ];
+                    (StatusCode::OK, events.join("\n") + "\n")
+                }
+                _ => message(StatusCode::NOT_FOUND, "not found"),
+            });
+            let starts: Vec<_> = container_starts(&docker)
+                .map(Result::unwrap)
+                .collect()
+                .await;
+            assert_eq!(
+                starts,
+                [
+                    ("0123abcd".to_string(), "app".to_string()),
+                    ("89abcdef".to_string(), "api".to_string()),
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn logs_stream_stdout_and_stderr() {
+            let docker = mock_docker(|_, path, _| match path {
+                "/containers/app/json" => json(
+                    StatusCode::OK,
+                    &ContainerInspectResponse {
+                        id: Some("0123abcd".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                // Each frame is the stream, three padding bytes and the length
+                "/containers/app/logs" => (
+                    StatusCode::OK,
+                    "\u{1}\0\0\0\0\0\0\u{6}hello\n\u{2}\0\0\0\0\0\0\u{5}oops\n".to_string(),
+                ),
+                _ => message(StatusCode::NOT_FOUND, "No such container"),
+            });
+            let logs = container_logs(&docker, "app", "10".to_string(), false)
+                .await
+                .unwrap()
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(logs.concat(), b"hello\noops\n".as_slice());
+            assert!(container_logs(&docker, "missing", "10".to_string(), false)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        #[tokio::test]
+        async fn stop_timeouts_reach_the_stop_call() {
+            let docker = mock_docker(|method, path, query| match (method, path, query) {
+                (&Method::POST, "/containers/app/stop", "t=30") => {
+                    (StatusCode::NO_CONTENT, String::new())
+                }
+                (&Method::DELETE, "/containers/app", _) => (StatusCode::NO_CONTENT, String::new()),
+                _ => message(StatusCode::NOT_FOUND, "No such container"),
+            });
+            stop_container(&docker, "app", Some(30)).await.unwrap();
+            assert!(stop_container(&docker, "app", Some(10)).await.is_err());
+        }
+
+        #[test]
+        fn overrides_replace_the_image_entrypoint_and_command() {
+            let args = |args: &[&str]| Some(args.iter().map(|arg| arg.to_string()).collect());
+            let image = || (args(&["/entrypoint.sh"]), args(&["serve"]));
+
+            let (entrypoint, cmd) = image();
+            assert_eq!(entrypoint_and_cmd(None, None, entrypoint, cmd), image());
+
+            let (entrypoint, cmd) = image();
+            assert_eq!(
+                entrypoint_and_cmd(None, args(&["migrate"]), entrypoint, cmd),
+                (args(&["/entrypoint.sh"]), args(&["migrate"]))
+            );
+
+            let (entrypoint, cmd) = image();
+            assert_eq!(
+                entrypoint_and_cmd(args(&["/bin/sh", "-c"]), None, entrypoint, cmd),
+                (args(&["/bin/sh", "-c"]), None)
+            );
+        }
+
+        #[tokio::test]
+        async fn managed_containers_are_found_by_their_name_label() {
+            let docker = mock_docker(|_, path, query| {
+                let filters = form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "filters")
+                    .map(|(_, filters)| filters.into_owned())
+                    .unwrap_or_default();
+                match path {
+                    "/containers/json" if filters == r#"{"label":["hermes.name=app"]}"# => json(
+                        StatusCode::OK,
+                        &[ContainerSummaryInner {
+                            id: Some("0123abcd".to_string()),
+                            state: Some("exited".to_string()),
+                            ..Default::default()
+                        }],
+                    ),
+                    "/containers/json" => (StatusCode::OK, "[]".to_string()),
+                    _ => message(StatusCode::NOT_FOUND, "not found"),
+                }
+            });
+            let found = find_managed_container(&docker, "app")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.id.as_deref(), Some("0123abcd"));
+            assert!(find_managed_container(&docker, "web")
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        /// Docker running hook containers that exit with `status`
+        fn hook_docker(status: i64) -> Docker {
+            mock_docker(move |method, path, _| match (method, path) {
+                (&Method::POST, "/containers/create") => json(
+                    StatusCode::CREATED,
+                    &serde_json::json!({ "Id": "hook1", "Warnings": [] }),
+                ),
+                (&Method::POST, "/containers/hook1/start") => {
+                    (StatusCode::NO_CONTENT, String::new())
+                }
+                (&Method::POST, "/containers/hook1/wait") => {
+                    json(StatusCode::OK, &serde_json::json!({ "StatusCode": status }))
+                }
+                (&Method::GET, "/containers/hook1/logs") => (
+                    StatusCode::OK,
+                    "\u{1}\0\0\0\0\0\0\u{9}migrated\n".to_string(),
+                ),
+                (&Method::DELETE, "/containers/hook1") => (StatusCode::NO_CONTENT, String::new()),
+                _ => message(StatusCode::NOT_FOUND, "not found"),
+            })
+        }
+
+        #[tokio::test]
+        async fn hooks_succeed_when_their_command_does() {
+            let config = config("url = \"/dev/null\"\npre_deploy = \"./migrate\"").await;
+            let cmd = config.pre_deploy.clone().unwrap();
+            assert_eq!(cmd, ["./migrate"]);
+            run_hook(&hook_docker(0), &config, "pre-deploy", &cmd)
+                .await
+                .unwrap();
+        }
+
+        #[tokio::test]
+        async fn hooks_fail_when_their_command_does() {
+            let config = config("url = \"/dev/null\"").await;
+            let why = run_hook(
+                &hook_docker(3),
+                &config,
+                "pre-deploy",
+                &["false".to_string()],
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(
+                why.to_string(),
+                "pre-deploy hook for app exited with status 3"
+            );
+        }
+
+        #[test]
+        fn containers_are_healthy_once_their_health_check_passes() {
+            let state = |running, status: Option<HealthStatusEnum>| ContainerState {
+                running: Some(running),
+                health: status.map(|status| Health {
+                    status: Some(status),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            assert!(is_healthy(&state(true, None)).unwrap());
+            assert!(is_healthy(&state(true, Some(HealthStatusEnum::HEALTHY))).unwrap());
+            assert!(!is_healthy(&state(true, Some(HealthStatusEnum::STARTING))).unwrap());
+            assert!(is_healthy(&state(true, Some(HealthStatusEnum::UNHEALTHY))).is_err());
+            assert!(is_healthy(&state(false, None)).is_err());
+        }
+
+        #[tokio::test]
+        async fn hooks_share_the_extra_hosts_of_their_container() {
+            let config = config(
+                "url = \"/dev/null\"\nextra_hosts = [\"db:10.0.0.2\", \"host:host-gateway\"]",
+            )
+            .await;
+            assert_eq!(
+                network_host_config(&config).extra_hosts.unwrap(),
+                ["db:10.0.0.2", "host:host-gateway"]
+            );
+        }
+
+        #[tokio::test]
+        async fn resolvers_reach_the_container_and_its_hooks() {
+            let config =
+                config("url = \"/dev/null\"\ndns = [\"10.0.0.53\"]\ndns_search = [\"svc\"]").await;
+            let network = network_host_config(&config);
+            assert_eq!(network.dns.unwrap(), ["10.0.0.53"]);
+            assert_eq!(network.dns_search.unwrap(), ["svc"]);
+
+            let created = created_container(&config).await;
+            assert_eq!(
+                created["HostConfig"]["Dns"],
+                serde_json::json!(["10.0.0.53"])
+            );
+            assert_eq!(
+                created["HostConfig"]["DnsSearch"],
+                serde_json::json!(["svc"])
+            );
+        }
+
+        #[tokio::test]
+        async fn hostnames_reach_the_container_config() {
+            let config =
+                config("url = \"/dev/null\"\nhostname = \"web\"\ndomainname = \"example.com\"")
+                    .await;
+            let created = created_container(&config).await;
+            assert_eq!(created["Hostname"], "web");
+            assert_eq!(created["Domainname"], "example.com");
+        }
+
+        #[tokio::test]
+        async fn takeovers_run_detached_from_the_new_image() {
+            let created = Arc::new(std::sync::Mutex::new(None));
+            let docker = mock_docker_with_body({
+                let created = created.clone();
+                move |method, path, _, body| match (method, path) {
+                    (&Method::POST, "/containers/create") => {
+                        *created.lock().unwrap() =
+                            Some(serde_json::from_slice::<serde_json::Value>(body).unwrap());
+                        json(
+                            StatusCode::CREATED,
+                            &serde_json::json!({ "Id": "takeover1", "Warnings": [] }),
+                        )
+                    }
+                    (&Method::POST, "/containers/takeover1/start") => {
+                        (StatusCode::NO_CONTENT, String::new())
+                    }
+                    _ => message(StatusCode::NOT_FOUND, "not found"),
+                }
+            });
+            let config = config("url = \"/dev/null\"").await;
+            assert_eq!(spawn_takeover(&docker, &config).await.unwrap(), "takeover1");
+
+            let created = created.lock().unwrap().take().unwrap();
+            assert_eq!(created["Image"], "app");
+            assert_eq!(created["Cmd"], serde_json::json!(["--takeover"]));
+            assert_eq!(created["HostConfig"]["AutoRemove"], true);
+            assert_eq!(created["Labels"][MANAGED_LABEL], "true");
+        }
+
+        #[tokio::test]
+        async fn gpus_reach_the_container_config() {
+            let created =
+                created_container(&config("url = \"/dev/null\"\ngpus = \"all\"").await).await;
+            assert_eq!(
+                created["HostConfig"]["DeviceRequests"],
+                serde_json::json!([{ "Driver": "nvidia", "Count": -1, "Capabilities": [["gpu"]] }])
+            );
+        }
+
+        #[tokio::test]
+        async fn gpu_containers_failing_to_start_point_at_the_daemon() {
+            let docker = mock_docker(|method, path, _| match (method, path) {
+                (&Method::GET, "/images/app/json") => json(
+                    StatusCode::OK,
+                    &Image {
+                        id: "sha256:1234".to_string(),
+                        ..Default::default()
+                    },
+                ),
+                (&Method::POST, "/containers/create") => json(
+                    StatusCode::CREATED,
+                    &serde_json::json!({ "Id": "0123abcd", "Warnings": [] }),
+                ),
+                _ => message(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not select device driver \"nvidia\"",
+                ),
+            });
+            let config = config("url = \"/dev/null\"\ngpus = 2").await;
+            let why = run_container(&docker, &config).await.unwrap_err();
+            assert!(
+                why.to_string().contains("NVIDIA container toolkit"),
+                "{}",
+                why
+            );
+        }
+
+        /// The requests of running `config` on a daemon whose first start of
+        /// the container fails with `failure`
+        async fn started_after(failure: &'static str) -> (Result<()>, Vec<String>) {
+            let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let docker = mock_docker({
+                let requests = requests.clone();
+                move |method, path, _| {
+                    let mut requests = requests.lock().unwrap();
+                    requests.push(format!("{} {}", method, path));
+                    let starts = requests.iter().filter(|req| req.ends_with("/start"));
+                    match (method, path) {
+                        (&Method::GET, "/images/app/json") => json(
+                            StatusCode::OK,
+                            &Image {
+                                id: "sha256:1234".to_string(),
+                                ..Default::default()
+                            },
+                        ),
+                        (&Method::POST, "/containers/create") => json(
+                            StatusCode::CREATED,
+                            &serde_json::json!({ "Id": "0123abcd", "Warnings": [] }),
+                        ),
+                        (&Method::POST, "/containers/0123abcd/start") if starts.count() == 1 => {
+                            message(StatusCode::INTERNAL_SERVER_ERROR, failure)
+                        }
+                        _ => (StatusCode::NO_CONTENT, String::new()),
+                    }
+                }
+            });
+            let config = config("url = \"/dev/null\"").await;
+            let started = run_container(&docker, &config).await;
+            let requests = requests.lock().unwrap().clone();
+            (started, requests)
+        }
+
+        #[tokio::test]
+        async fn transient_start_failures_are_retried() {
+            let (started, requests) = started_after("port is already allocated").await;
+            started.unwrap();
+            assert_eq!(
+                requests,
+                [
+                    "GET /images/app/json",
+                    "POST /containers/create",
+                    "POST /containers/0123abcd/start",
+                    "DELETE /containers/0123abcd",
+                    "GET /images/app/json",
+                    "POST /containers/create",
+                    "POST /containers/0123abcd/start",
+                ]
+            );
+
+            let (started, requests) = started_after("invalid mount config").await;
+            assert!(started.is_err());
+            assert_eq!(
+                requests
+                    .iter()
+                    .filter(|req| *req == "POST /containers/create")
+                    .count(),
+                1
+            );
+        }
+
+        /// The images `cleanup_old_images` removes of `app`, which has a current
+        /// image and ones from 10 minutes and 2, 3 and 4 days ago, the one from
+        /// 3 days ago being used by a container
+        async fn removed_images(keep: Option<usize>, max_age: Option<Duration>) -> Vec<String> {
+            let removed = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let docker = mock_docker({
+                let removed = removed.clone();
+                move |method, path, _| {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    let image = |id: &str, tag: &str, age: u64| ImageSummary {
+                        id: id.to_string(),
+                        repo_tags: vec![["app:", tag].concat()],
+                        created: (now.as_secs() - age) as i64,
+                        ..Default::default()
+                    };
+                    const DAY: u64 = 24 * 60 * 60;
+                    match (method, path) {
+                        (&Method::GET, "/images/json") => json(
+                            StatusCode::OK,
+                            &[
+                                image("current", "latest", 0),
+                                image("young", "aaaa", 10 * 60),
+                                image("old", "bbbb", 2 * DAY),
+                                image("used", "cccc", 3 * DAY),
+                                image("oldest", "dddd", 4 * DAY),
+                            ],
+                        ),
+                        (&Method::GET, "/containers/json") => json(
+                            StatusCode::OK,
+                            &serde_json::json!([{ "Id": "0123abcd", "ImageID": "used" }]),
+                        ),
+                        (&Method::DELETE, path) => {
+                            let id = path.strip_prefix("/images/").unwrap();
+                            removed.lock().unwrap().push(id.to_string());
+                            (StatusCode::OK, "[]".to_string())
+                        }
+                        _ => message(StatusCode::NOT_FOUND, "not found"),
+                    }
+                }
+            });
+            let count = cleanup_old_images(&docker, "app", keep, max_age)
+                .await
+                .unwrap();
+            let removed = removed.lock().unwrap().clone();
+            assert_eq!(count, removed.len());
+            removed
+        }
+
+        #[tokio::test]
+        async fn old_images_are_removed_unless_recent_young_or_used() {
+            let day = Duration::from_secs(24 * 60 * 60);
+            assert_eq!(removed_images(Some(2), None).await, ["oldest"]);
+            assert_eq!(removed_images(None, Some(day)).await, ["old", "oldest"]);
+            assert_eq!(removed_images(Some(1), Some(day)).await, ["old", "oldest"]);
+            assert_eq!(removed_images(Some(1), Some(day * 5 / 2)).await, ["oldest"]);
+            assert_eq!(removed_images(Some(3), Some(day)).await, ["oldest"]);
+        }
+
+        #[tokio::test]
+        async fn user_labels_coexist_with_the_management_labels() {
+            let config = config(
+                "url = \"/dev/null\"\n[labels]\n\"traefik.enable\" = \"true\"\n\
+                \"hermes.name\" = \"other\"",
+            )
+            .await;
+            let labels = &created_container(&config).await["Labels"];
+            assert_eq!(labels["traefik.enable"], "true");
+            assert_eq!(labels[MANAGED_LABEL], "true");
+            assert_eq!(labels[NAME_LABEL], "app");
+        }
+
+        #[tokio::test]
+        async fn docker_sockets_are_bound_with_a_warning() {
+            crate::tests::test_env();
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created["HostConfig"]["Binds"].is_null());
+
+            let config = config("url = \"/dev/null\"\ndocker_socket = true").await;
+            let created = created_container(&config).await;
+            assert_eq!(
+                created["HostConfig"]["Binds"],
+                serde_json::json!(["/var/run/docker.sock:/var/run/docker.sock"])
+            );
+            let logs = crate::tests::LOGS.lock().unwrap();
+            assert!(logs
+                .iter()
+                .any(|log| log.starts_with("Container app has the Docker socket mounted")));
+        }
+
+        #[tokio::test]
+        async fn workdirs_reach_the_container_config() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created["WorkingDir"].is_null());
+
+            let config = config("url = \"/dev/null\"\nworkdir = \"/srv/app\"").await;
+            assert_eq!(created_container(&config).await["WorkingDir"], "/srv/app");
+        }
+
+        #[tokio::test]
+        async fn ulimits_reach_the_host_config() {
+            let config = config("url = \"/dev/null\"\n[ulimits]\nnofile = \"1024:4096\"").await;
+            let created = created_container(&config).await;
+            assert_eq!(
+                created["HostConfig"]["Ulimits"],
+                serde_json::json!([{ "Name": "nofile", "Soft": 1024, "Hard": 4096 }])
+            );
+        }
+
+        #[tokio::test]
+        async fn users_reach_the_container_config() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created["User"].is_null());
+
+            let config = config("url = \"/dev/null\"\nuser = \"node:node\"").await;
+            assert_eq!(created_container(&config).await["User"], "node:node");
+        }
+
+        #[tokio::test]
+        async fn sysctls_reach_the_host_config() {
+            let config =
+                config("url = \"/dev/null\"\n[sysctls]\n\"net.core.somaxconn\" = \"1024\"").await;
+            let created = created_container(&config).await;
+            assert_eq!(
+                created["HostConfig"]["Sysctls"],
+                serde_json::json!({ "net.core.somaxconn": "1024" })
+            );
+        }
+
+        #[tokio::test]
+        async fn blkio_weights_and_pids_limits_reach_the_host_config() {
+            let config = config("url = \"/dev/null\"\nblkio_weight = 300\npids_limit = 64").await;
+            let created = created_container(&config).await;
+            assert_eq!(created["HostConfig"]["BlkioWeight"], 300);
+            assert_eq!(created["HostConfig"]["PidsLimit"], 64);
+        }
+
+        #[tokio::test]
+        async fn init_reaches_the_host_config() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created["HostConfig"]["Init"].is_null());
+
+            let config = config("url = \"/dev/null\"\ninit = true").await;
+            assert_eq!(created_container(&config).await["HostConfig"]["Init"], true);
+        }
+
+        #[tokio::test]
+        async fn stop_signals_are_set_on_creation() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created.get("StopSignal").is_none());
+
+            let config = config("url = \"/dev/null\"\nstop_signal = \"SIGQUIT\"").await;
+            assert_eq!(created_container(&config).await["StopSignal"], "SIGQUIT");
+        }
+
+        #[tokio::test]
+        async fn memory_limits_are_set_on_the_host_config() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert_eq!(created["HostConfig"]["OomKillDisable"], false);
+            assert!(created["HostConfig"].get("Memory").is_none());
+
+            let config = config(
+                "url = \"/dev/null\"\nmemory = \"64m\"\nmemory_swap = -1\noom_kill_disable = true",
+            )
+            .await;
+            let host_config = &created_container(&config).await["HostConfig"];
+            assert_eq!(host_config["Memory"], 64 << 20);
+            assert_eq!(host_config["MemorySwap"], -1);
+            assert_eq!(host_config["OomKillDisable"], true);
+        }
+
+        #[tokio::test]
+        async fn devices_are_mapped_on_the_host_config() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created["HostConfig"].get("Devices").is_none());
+
+            let config =
+                config("url = \"/dev/null\"\ndevices = [\"/dev/ttyUSB0:/dev/ttyS0:rw\"]").await;
+            let devices = &created_container(&config).await["HostConfig"]["Devices"];
+            assert_eq!(
+                *devices,
+                serde_json::json!([{
+                    "PathOnHost": "/dev/ttyUSB0",
+                    "PathInContainer": "/dev/ttyS0",
+                    "CgroupPermissions": "rw",
+                }])
+            );
+        }
+
+        #[tokio::test]
+        async fn aliases_and_static_ips_are_set_on_the_network_endpoint() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert!(created.get("NetworkingConfig").is_none());
+
+            let config = config(
+                "url = \"/dev/null\"\nnetwork = \"backend\"\n\
+                network_aliases = [\"api\"]\nip = \"10.1.0.10\"",
+            )
+            .await;
+            let created = created_container(&config).await;
+            assert_eq!(created["HostConfig"]["NetworkMode"], "backend");
+            let endpoint = &created["NetworkingConfig"]["EndpointsConfig"]["backend"];
+            assert_eq!(endpoint["Aliases"], serde_json::json!(["api"]));
+            assert_eq!(endpoint["IPAMConfig"]["IPv4Address"], "10.1.0.10");
+            assert!(endpoint["IPAMConfig"].get("IPv6Address").is_none());
+        }
+
+        #[tokio::test]
+        async fn static_ips_must_be_in_the_network_subnet() {
+            let docker = mock_docker(|_, path, _| match path {
+                "/networks/backend" => json(StatusCode::OK, &backend()),
+                _ => message(StatusCode::NOT_FOUND, "not found"),
+            });
+            check_subnet(&docker, "backend", "10.1.2.3".parse().unwrap())
+                .await
+                .unwrap();
+            let why = check_subnet(&docker, "backend", "10.2.0.1".parse().unwrap())
+                .await
+                .unwrap_err();
+            assert!(why.to_string().contains("outside the subnets"), "{}", why);
+            // The network has no IPv6 subnet to check against
+            check_subnet(&docker, "backend", "fd00::1".parse().unwrap())
+                .await
+                .unwrap();
+        }
+
+        #[tokio::test]
+        async fn secrets_and_configs_are_mounted_read_only() {
+            let dir =
+                std::env::temp_dir().join(format!("hermes-test-secrets-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("db_password"), "hunter2").unwrap();
+            std::fs::write(dir.join("nginx.conf"), "events {}").unwrap();
+            let config = config(&format!(
+                "url = \"/dev/null\"\nvolumes = {{ app-data = \"/data\" }}\n\
+                [secrets]\ndb_password = {:?}\n[configs]\n\"nginx.conf\" = {:?}",
+                dir.join("db_password"),
+                dir.join("nginx.conf")
+            ))
+            .await;
+            let mounts = created_container(&config).await["HostConfig"]["Mounts"].clone();
+            let mount = |source: &Path, target: &str| {
+                serde_json::json!({
+                    "Source": source,
+                    "Target": target,
+                    "Type": "bind",
+                    "ReadOnly": true,
+                })
+            };
+            assert_eq!(mounts.as_array().unwrap().len(), 3);
+            assert_eq!(mounts[0]["Source"], "app-data");
+            assert_eq!(
+                mounts[1],
+                mount(&dir.join("db_password"), "/run/secrets/db_password")
+            );
+            assert_eq!(mounts[2], mount(&dir.join("nginx.conf"), "/nginx.conf"));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[tokio::test]
+        async fn read_only_roots_keep_their_writable_mounts() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert_eq!(created["HostConfig"]["ReadonlyRootfs"], false);
+
+            let config = config(
+                "url = \"/dev/null\"\nread_only = true\n[tmpfs]\n\"/tmp\" = \"\"\n\
+                [volumes]\n\"/srv/data\" = \"/data\"",
+            )
+            .await;
+            let host_config = &created_container(&config).await["HostConfig"];
+            assert_eq!(host_config["ReadonlyRootfs"], true);
+            assert_eq!(host_config["Tmpfs"], serde_json::json!({ "/tmp": "" }));
+            assert_eq!(host_config["Binds"], serde_json::json!(["/srv/data:/data"]));
+        }
+
+        #[tokio::test]
+        async fn capabilities_reach_the_container_config() {
+            let created = created_container(&config("url = \"/dev/null\"").await).await;
+            assert_eq!(created["HostConfig"]["Privileged"], false);
+
+            let config = config(
+                "url = \"/dev/null\"\ncap_add = [\"net_admin\", \"CAP_SYS_PTRACE\"]\n\
+                cap_drop = [\"ALL\"]\nprivileged = true",
+            )
+            .await;
+            let created = created_container(&config).await;
+            assert_eq!(
+                created["HostConfig"]["CapAdd"],
+                serde_json::json!(["NET_ADMIN", "SYS_PTRACE"])
+            );
+            assert_eq!(created["HostConfig"]["CapDrop"], serde_json::json!(["ALL"]));
+            assert_eq!(created["HostConfig"]["Privileged"], true);
+        }
+    }
+}
+
+pub mod tls {
+    use anyhow::{bail, Context, Result};
+    use futures::{
+        future,
+        stream::{self, Stream, StreamExt},
+    };
+    use rustls_pemfile::Item;
+    use std::{
+        fs::File, io, io::BufReader, net::SocketAddr, path::Path, sync::Arc, time::Duration,
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time,
+    };
+    use tokio_rustls::{
+        rustls::{Certificate, PrivateKey, ServerConfig},
+        server::TlsStream,
+        TlsAcceptor,
+    };
+
+    // Maximum number of TLS handshakes in flight at once
+    const MAX_HANDSHAKES: usize = 64;
+    // Clients that don't finish the handshake in time are dropped, so stalled
+    // ones can't hold every handshake slot
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+    // Pause after a failed accept, which tends to fail again right away when
+    // it's out of file descriptors
+    const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+    pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+        let cert_file = File::open(cert_path)
+            .context(format!("unable to open TLS certificate {:#?}", cert_path))?;
+        let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .context(format!("unable to parse TLS certificate {:#?}", cert_path))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            bail!("no certificates found in {:#?}", cert_path);
+        }
+
+        let key_file =
+            File::open(key_path).context(format!("unable to open TLS key {:#?}", key_path))?;
+        let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+            .context(format!("unable to parse TLS key {:#?}", key_path))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .context(format!("no private key found in {:#?}", key_path))?;
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("invalid TLS certificate or key")?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    pub async fn incoming(
+        acceptor: TlsAcceptor,
+        addr: SocketAddr,
+    ) -> Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("unable to bind to {}", addr))?;
+
+        Ok(handshakes(listener, acceptor, HANDSHAKE_TIMEOUT))
+    }
+
+    fn handshakes(
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+        timeout: Duration,
+    ) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+        stream::unfold(listener, |listener| async {
+            loop {
+                match listener.accept().await {
+                    Ok(conn) => return Some((conn, listener)),
+                    Err(why) => {
+                        error!("Failed to accept connection: {}", why);
+                        time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                }
+            }
+        })
+        .map(move |(stream, peer)| {
+            let acceptor = acceptor.clone();
+            async move {
+                match time::timeout(timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => Some(Ok(stream)),
+                    Ok(Err(why)) => {
+                        trace!("TLS handshake with {} failed: {}", peer, why);
+                        None
+                    }
+                    Err(_) => {
+                        trace!("TLS handshake with {} timed out", peer);
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(future::ready)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio::io::AsyncReadExt;
+        use tokio_rustls::rustls::{
+            server::{ClientHello, ResolvesServerCert},
+            sign::CertifiedKey,
+        };
+
+        // The handshakes under test never get as far as picking a certificate
+        struct NoCert;
+
+        impl ResolvesServerCert for NoCert {
+            fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+                None
+            }
+        }
+
+        #[tokio::test]
+        async fn stalled_handshakes_are_dropped() {
+            let config = ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(NoCert));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let incoming = handshakes(
+                listener,
+                TlsAcceptor::from(Arc::new(config)),
+                Duration::from_millis(50),
+            );
+            tokio::spawn(incoming.for_each(|_| future::ready(())));
+
+            // A client that never sends its hello is disconnected once the
+            // handshake times out
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let read = time::timeout(Duration::from_secs(5), client.read(&mut [0; 1])).await;
+            assert_eq!(read.unwrap().unwrap(), 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_must_be_single_path_components() {
+        for name in ["app", "my-app_2", "app.v2"] {
+            assert!(sanitize_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            ".app",
+            "-app",
+            "..",
+            "../..",
+            "app/../etc",
+            "a..b",
+            "app name",
+            "app/web",
+        ] {
+            assert!(sanitize_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn log_tails_keep_the_end_of_the_output() {
+        let mut tail = LogTail::new(8);
+        tail.push(b"first\n");
+        assert_eq!(LogTail::new(8).finish(), "");
+        tail.push(b"second\n");
+        for _ in 0..10 {
+            tail.push(b"x");
+        }
+        tail.push(b"\nend");
+        assert_eq!(tail.finish(), "[19 bytes truncated]\nxxxx\nend");
+
+        let mut tail = LogTail::new(8);
+        tail.push(b"short");
+        assert_eq!(tail.finish(), "short");
+    }
+
+    #[test]
+    fn env_vars_can_be_read_from_files() {
+        let path = env::temp_dir().join(format!("hermes-test-secret-{}", std::process::id()));
+        fs::write(&path, "from file\r\n\n").unwrap();
+
+        assert_eq!(env_or_file("HERMES_TEST_SECRET").unwrap(), None);
+        env::set_var("HERMES_TEST_SECRET", "inline");
+        assert_eq!(
+            env_or_file("HERMES_TEST_SECRET").unwrap().as_deref(),
+            Some("inline")
+        );
+        // The file wins over the inline value, without its trailing newlines
+        env::set_var("HERMES_TEST_SECRET_FILE", &path);
+        assert_eq!(
+            env_or_file("HERMES_TEST_SECRET").unwrap().as_deref(),
+            Some("from file")
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert!(env_or_file("HERMES_TEST_SECRET").is_err());
+    }
 }